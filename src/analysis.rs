@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::midi::Midi;
+
+const NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Hit rate of the song split in several ways
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    /// Every merged note
+    pub all: Stat,
+    /// Each track by index, including the disabled ones
+    pub tracks: Vec<(usize, String, Stat)>,
+    /// Each octave, middle C is in octave 4
+    pub octaves: BTreeMap<i32, Stat>,
+    /// Each pitch class from C to B
+    pub pitch_classes: [Stat; 12],
+    /// Notes higher than the highest playable note
+    pub above: usize,
+    /// Notes lower than the lowest playable note
+    pub below: usize,
+    /// Unplayable notes and how many times they occur, most frequent first
+    pub missing: Vec<(i32, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub hits: usize,
+    pub total: usize,
+}
+
+impl Stat {
+    #[inline]
+    fn add(&mut self, hit: bool) {
+        self.hits += hit as usize;
+        self.total += 1;
+    }
+
+    /// Part of the notes that are hit, 0 without notes
+    pub fn rate(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.hits as f32 / self.total as f32
+    }
+}
+
+/// Scientific pitch name of a MIDI note, e.g. 60 is C4
+pub fn note_name(note: i32) -> String {
+    format!(
+        "{}{}",
+        NAMES[note.rem_euclid(12) as usize],
+        note.div_euclid(12) - 1
+    )
+}
+
+pub fn pitch_class_name(class: usize) -> &'static str {
+    NAMES[class % 12]
+}
+
+impl Midi {
+    pub fn analyze(&self, offset: i32) -> Analysis {
+        const MISSING: usize = 10;
+
+        let profile = self.profile.read();
+        let fold = self.fold.load();
+        let bounds = profile.bounds();
+        let mut analysis = Analysis::default();
+        let mut missing = HashMap::new();
+        self.events
+            .read()
+            .iter()
+            .filter(|e| !e.percussion)
+            .for_each(|e| {
+                let note = e.press + offset;
+                let hit = fold.apply(note, &profile).is_some();
+                analysis.all.add(hit);
+                analysis
+                    .octaves
+                    .entry(note.div_euclid(12) - 1)
+                    .or_default()
+                    .add(hit);
+                analysis.pitch_classes[note.rem_euclid(12) as usize].add(hit);
+                if !hit {
+                    *missing.entry(note).or_insert(0) += 1;
+                }
+                match bounds {
+                    Some((_, high)) if note > high => analysis.above += 1,
+                    Some((low, _)) if note < low => analysis.below += 1,
+                    _ => {}
+                }
+            });
+        analysis.tracks = self
+            .track_num
+            .read()
+            .iter()
+            .filter_map(|(_, index, name)| {
+                let mut stat = Stat::default();
+                self.track_notes(*index)
+                    .into_iter()
+                    .for_each(|note| stat.add(fold.apply(note + offset, &profile).is_some()));
+                (stat.total > 0).then(|| (*index, name.clone(), stat))
+            })
+            .collect();
+        analysis.missing = missing.into_iter().collect();
+        analysis
+            .missing
+            .sort_by(|(a, count_a), (b, count_b)| count_b.cmp(count_a).then(a.cmp(b)));
+        analysis.missing.truncate(MISSING);
+        analysis
+    }
+}
@@ -0,0 +1,202 @@
+use std::cmp::Reverse;
+
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumIter};
+
+use crate::midi::Event;
+
+/// Which notes of a chord are kept when it has more notes than the limit
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumIter, AsRefStr,
+)]
+pub enum Strategy {
+    /// Keep the highest notes
+    #[default]
+    Highest,
+    /// Keep the lowest notes
+    Lowest,
+    /// Keep the highest and the lowest note, then the highest of the rest
+    MelodyBass,
+    /// Keep every note, rolled from low to high in groups of at most the limit,
+    /// `spread` milliseconds apart
+    Arpeggio { spread: u32 },
+}
+
+/// Marks the first event of each chord with the number of events played together
+pub fn group(events: &mut [Event]) {
+    let mut start = 0;
+    for i in 0..events.len() {
+        if events[i].delay > 0.0 {
+            start = i;
+        }
+        events[i].chord = 0;
+        events[start].chord += 1;
+    }
+}
+
+/// How many notes are pressed at the same instant
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChordLimit {
+    /// The most notes of a chord, the `max_chord` of the profile when unset
+    pub max: Option<usize>,
+    pub strategy: Strategy,
+}
+
+impl ChordLimit {
+    /// Limits every chord of `events`, `profile_max` is used when no limit is set
+    pub fn apply(self, events: Vec<Event>, profile_max: Option<usize>) -> Vec<Event> {
+        let Some(max) = self.max.or(profile_max).filter(|max| *max > 0) else {
+            return events;
+        };
+        let mut limited = Vec::with_capacity(events.len());
+        // A chord due before an arpeggio ends comes this long after it, never together with it
+        let gap = match self.strategy {
+            Strategy::Arpeggio { spread } => spread.max(1) as f32 * 1000.0,
+            _ => 0.0,
+        };
+        // Microseconds the previous arpeggios ran into the following notes
+        let mut lag = 0.0;
+        for chord in events.chunk_by(|_, next| next.delay == 0.0) {
+            let delay = chord[0].delay;
+            let first = if lag > 0.0 && delay <= lag {
+                lag += gap - delay;
+                gap
+            } else {
+                let first = delay - lag;
+                lag = 0.0;
+                first
+            };
+            let mut notes = chord.to_vec();
+            match self.strategy {
+                Strategy::Highest => {
+                    notes.sort_by_key(|e| Reverse(e.press));
+                    notes.truncate(max);
+                }
+                Strategy::Lowest => {
+                    notes.sort_by_key(|e| e.press);
+                    notes.truncate(max);
+                }
+                Strategy::MelodyBass => {
+                    notes.sort_by_key(|e| Reverse(e.press));
+                    if notes.len() > max {
+                        let bass = notes[notes.len() - 1];
+                        notes.truncate(max);
+                        if max > 1 {
+                            notes[max - 1] = bass;
+                        }
+                    }
+                }
+                Strategy::Arpeggio { .. } => {
+                    notes.sort_by_key(|e| e.press);
+                    for (i, group) in notes.chunks(max).enumerate() {
+                        group.iter().enumerate().for_each(|(j, e)| {
+                            limited.push(Event {
+                                delay: match (i, j) {
+                                    (0, 0) => first,
+                                    (_, 0) => gap,
+                                    _ => 0.0,
+                                },
+                                ..*e
+                            })
+                        });
+                        if i > 0 {
+                            lag += gap;
+                        }
+                    }
+                    continue;
+                }
+            }
+            notes.iter().enumerate().for_each(|(i, e)| {
+                limited.push(Event {
+                    delay: if i == 0 { first } else { 0.0 },
+                    ..*e
+                })
+            });
+        }
+        limited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chord of `notes` after `delay` milliseconds
+    fn chord(delay: f32, notes: &[i32]) -> Vec<Event> {
+        notes
+            .iter()
+            .enumerate()
+            .map(|(i, press)| Event::note(*press, if i == 0 { delay * 1000.0 } else { 0.0 }))
+            .collect()
+    }
+
+    fn limit(strategy: Strategy, max: usize, events: Vec<Event>) -> Vec<(i32, f32)> {
+        ChordLimit {
+            max: Some(max),
+            strategy,
+        }
+        .apply(events, None)
+        .iter()
+        .map(|e| (e.press, e.delay / 1000.0))
+        .collect()
+    }
+
+    #[test]
+    fn unlimited() {
+        let events = chord(10.0, &[60, 64, 67]);
+        assert_eq!(ChordLimit::default().apply(events.clone(), None).len(), 3);
+        assert_eq!(ChordLimit::default().apply(events, Some(2)).len(), 2);
+    }
+
+    #[test]
+    fn strategies() {
+        let events = chord(10.0, &[64, 48, 72, 60]);
+        let mut highest = limit(Strategy::Highest, 2, events.clone());
+        highest.sort_by_key(|(press, _)| *press);
+        assert_eq!(highest.iter().map(|e| e.0).collect::<Vec<_>>(), [64, 72]);
+        assert_eq!(highest.iter().map(|e| e.1).sum::<f32>(), 10.0);
+
+        let lowest = limit(Strategy::Lowest, 2, events.clone());
+        assert_eq!(lowest, [(48, 10.0), (60, 0.0)]);
+
+        let melody_bass = limit(Strategy::MelodyBass, 2, events.clone());
+        assert_eq!(melody_bass, [(72, 10.0), (48, 0.0)]);
+        let melody_bass = limit(Strategy::MelodyBass, 3, events);
+        assert_eq!(melody_bass, [(72, 10.0), (64, 0.0), (48, 0.0)]);
+    }
+
+    #[test]
+    fn arpeggio_delays_the_following_notes() {
+        let arpeggio = Strategy::Arpeggio { spread: 30 };
+        let mut events = chord(0.0, &[67, 60, 64]);
+        events.extend(chord(100.0, &[72]));
+        assert_eq!(
+            limit(arpeggio, 1, events),
+            [(60, 0.0), (64, 30.0), (67, 30.0), (72, 40.0)]
+        );
+
+        // A chord coming before the arpeggio ends is played a spread after it
+        let mut events = chord(0.0, &[60, 64, 67]);
+        events.extend(chord(50.0, &[72]));
+        events.extend(chord(100.0, &[74]));
+        assert_eq!(
+            limit(arpeggio, 1, events.clone()),
+            [(60, 0.0), (64, 30.0), (67, 30.0), (72, 30.0), (74, 60.0)]
+        );
+        // So it is never pressed together with the end of the arpeggio
+        let mut limited = ChordLimit {
+            max: Some(1),
+            strategy: arpeggio,
+        }
+        .apply(events, None);
+        group(&mut limited);
+        assert!(limited.iter().all(|e| e.chord <= 1), "{limited:?}");
+
+        // Notes are rolled in groups of the limit
+        let events = chord(0.0, &[60, 64, 67, 72]);
+        assert_eq!(
+            limit(arpeggio, 2, events),
+            [(60, 0.0), (64, 0.0), (67, 30.0), (72, 0.0)]
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread::sleep;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use strum::IntoEnumIterator;
+
+use lyred::analysis::note_name;
+use lyred::chord::{ChordLimit, Strategy};
+use lyred::ghost::GhostFilter;
+use lyred::maps::Fold;
+use lyred::melody::{Skyline, Voice};
+use lyred::midi::{Division, LoadError, Midi, Percussion, Split, Sustain};
+use lyred::output::{Backend, KeyOutput, Recorder};
+use lyred::player::{LoopRegion, Player, Settings, DEFAULT_SPIN};
+use lyred::profile::{InstrumentProfile, Profiles, DRUMS};
+use lyred::tempo::{parse_bar, parse_time};
+use lyred::ui::play::Config;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "lyred",
+    version,
+    about = "Lyred is a MIDI player in various games",
+    after_help = "Exit codes: 1 hit rate below --min, 2 bad arguments or unknown profile, \
+                  3 unreadable file, 4 invalid MIDI, 5 unsupported timing, 6 no notes"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Play a MIDI file without opening a window
+    Play {
+        #[command(flatten)]
+        song: Song,
+        /// Playback speed
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f32,
+        /// Seconds to wait before the first note, e.g. to focus the game window
+        #[arg(long, default_value_t = 0.0)]
+        delay: f32,
+        /// Key output backend, `Record` prints the keys instead of sending them
+        #[arg(short, long, default_value = "System", value_parser = parse_backend)]
+        backend: Backend,
+        /// Hold every key for the length of its note instead of tapping it
+        #[arg(long)]
+        hold: bool,
+        /// The longest time in milliseconds a key is held for
+        #[arg(long, requires = "hold")]
+        hold_cap: Option<u32>,
+        /// Microseconds before each note that are spun instead of slept
+        #[arg(long, default_value_t = DEFAULT_SPIN)]
+        spin: u32,
+        /// Print how late the notes were sent
+        #[arg(long)]
+        timing: bool,
+        /// Start at a time, as mm:ss or seconds
+        #[arg(long, value_parser = parse_start, conflicts_with = "bar")]
+        start: Option<usize>,
+        /// Start at a bar, as bar or bar.beat
+        #[arg(long, value_parser = parse_start_bar)]
+        bar: Option<(u32, u32)>,
+        /// Loop the passage between two times, as mm:ss or seconds
+        #[arg(long, num_args = 2, value_names = ["A", "B"], value_parser = parse_start)]
+        r#loop: Vec<usize>,
+        /// Passes of the loop, it loops until stopped by default
+        #[arg(long, requires = "loop")]
+        times: Option<u32>,
+        /// Beats waited before each pass of the loop
+        #[arg(long, default_value_t = 0, requires = "loop")]
+        count_in: u32,
+        /// Percent of the speed taken off on every repetition of the loop
+        #[arg(long, default_value_t = 0.0, requires = "loop")]
+        slow_down: f32,
+    },
+    /// Print the tracks, length and hit rate of a MIDI file
+    Info {
+        #[command(flatten)]
+        song: Song,
+    },
+    /// Convert a MIDI file into text sheets
+    Convert {
+        #[command(flatten)]
+        song: Song,
+        /// Name of the generated sheets, defaults to the file name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Put every bar on its own line
+        #[arg(long)]
+        bars: bool,
+    },
+    /// List the instrument profiles
+    Profiles,
+    /// Exit with an error when the hit rate is below the threshold
+    Check {
+        #[command(flatten)]
+        song: Song,
+        /// Minimum hit rate in percent
+        #[arg(long, default_value_t = 100.0)]
+        min: f32,
+    },
+}
+
+#[derive(Debug, Args)]
+struct Song {
+    /// Path to the MIDI file
+    file: PathBuf,
+    /// Indices of the tracks to merge, all by default
+    #[arg(short, long, value_delimiter = ',')]
+    tracks: Vec<usize>,
+    /// Offset in semitones
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    offset: i32,
+    /// Instrument profile
+    #[arg(short, long, alias = "mode", default_value = "GenShin")]
+    profile: String,
+    /// Most notes pressed at the same instant, the limit of the profile by default
+    #[arg(long)]
+    max_chord: Option<usize>,
+    /// Which notes of a larger chord are kept
+    #[arg(long, default_value = "Highest", value_parser = parse_strategy)]
+    chord: Strategy,
+    /// Milliseconds between the notes of an arpeggio
+    #[arg(long, default_value_t = 30)]
+    spread: u32,
+    /// How the tracks of the file are divided
+    #[arg(long, default_value = "Channel", value_parser = parse_split)]
+    split: Split,
+    /// What to do with the notes of the General MIDI percussion channel
+    #[arg(long, default_value = "Skip", value_parser = parse_percussion)]
+    percussion: Percussion,
+    /// What to do with notes out of the playable range
+    #[arg(short, long, default_value = "Drop", value_parser = parse_fold)]
+    fold: Fold,
+    /// Profile percussion is played with when it is mapped to drums
+    #[arg(long, default_value = DRUMS)]
+    drums: String,
+    /// Indices of the tracks left out
+    #[arg(long, value_delimiter = ',')]
+    mute: Vec<usize>,
+    /// Indices of the only tracks played
+    #[arg(long, value_delimiter = ',')]
+    solo: Vec<usize>,
+    /// Notes of every track played softer than this are left out
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=127))]
+    min_velocity: Option<u8>,
+    /// Notes played softer than this are ghost notes, thinned out by `--ghost-gap`
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=127))]
+    ghost_velocity: u8,
+    /// Milliseconds after a played note in which ghost notes are left out
+    #[arg(long, default_value_t = 0)]
+    ghost_gap: u32,
+    /// Keep only the top note of each track or of the merged tracks
+    #[arg(long, default_value = "Off", value_parser = parse_voice)]
+    melody: Voice,
+    /// Milliseconds in which notes starting later count as starting together for `--melody`
+    #[arg(long, default_value_t = 0)]
+    melody_window: u32,
+}
+
+impl Song {
+    fn load(&self, profiles: &Profiles) -> Result<Midi, ExitCode> {
+        let mut config = config();
+        let Some(profile) = profile(profiles, &self.profile, config.as_mut()) else {
+            eprintln!(
+                "unknown profile {}, expected one of {:?}",
+                self.profile,
+                profiles.names()
+            );
+            return Err(ExitCode::from(2));
+        };
+        let midi = Midi::new();
+        *midi.profile.write() = profile;
+        midi.chord.store(ChordLimit {
+            max: self.max_chord,
+            strategy: match self.chord {
+                Strategy::Arpeggio { .. } => Strategy::Arpeggio {
+                    spread: self.spread,
+                },
+                strategy => strategy,
+            },
+        });
+        midi.ghost.store(GhostFilter {
+            min_velocity: 0,
+            ghost_velocity: self.ghost_velocity,
+            min_gap: self.ghost_gap,
+        });
+        midi.melody.store(Skyline {
+            voice: self.melody,
+            window: self.melody_window,
+        });
+        midi.split.store(self.split);
+        midi.percussion.store(self.percussion);
+        midi.fold.store(self.fold);
+        let Some(drums) = profiles.get(&self.drums) else {
+            eprintln!(
+                "unknown profile {}, expected one of {:?}",
+                self.drums,
+                profiles.names()
+            );
+            return Err(ExitCode::from(2));
+        };
+        *midi.drums.write() = drums.clone();
+        if let Err(e) = midi.read_midi(&self.file) {
+            eprintln!("failed to load {}: {e}", self.file.display());
+            return Err(ExitCode::from(match e {
+                LoadError::Io(_) => 3,
+                LoadError::Parse(_) => 4,
+                LoadError::UnsupportedTiming(_) => 5,
+                LoadError::Empty | LoadError::OnlyPercussion => 6,
+            }));
+        }
+        if !self.tracks.is_empty() {
+            midi.track_num
+                .write()
+                .iter_mut()
+                .for_each(|(enable, index, _)| *enable = self.tracks.contains(index));
+        }
+        let name = midi.name.read().clone().unwrap_or_default();
+        let saved = config
+            .and_then(|mut config| config.mixes.remove(&name))
+            .unwrap_or_default();
+        let mut mixer = midi.mixer.write();
+        mixer
+            .iter_mut()
+            .zip(saved)
+            .for_each(|(to, from)| *to = from);
+        for (index, mix) in mixer.iter_mut().enumerate() {
+            mix.mute |= self.mute.contains(&index);
+            mix.solo |= self.solo.contains(&index);
+            if let Some(velocity) = self.min_velocity {
+                mix.min_velocity = velocity;
+            }
+        }
+        drop(mixer);
+        midi.merge_tracks(&midi.current_range(), self.offset);
+        if midi.events.read().is_empty() {
+            eprintln!(
+                "no notes left to play in {}, check --tracks, --mute, --solo and --percussion",
+                self.file.display()
+            );
+            return Err(ExitCode::from(6));
+        }
+        Ok(midi)
+    }
+}
+
+/// The settings saved by the window in `config.ron`
+fn config() -> Option<Config> {
+    let file = std::fs::read_to_string("config.ron").ok()?;
+    let mut config = ron::from_str::<Config>(&file).ok()?;
+    config.migrate();
+    Some(config)
+}
+
+/// The profile with the keys replaced in the config
+fn profile(
+    profiles: &Profiles,
+    name: &str,
+    config: Option<&mut Config>,
+) -> Option<InstrumentProfile> {
+    let profile = profiles.get(name)?;
+    let remap = config.and_then(|config| config.keys.remove(&profile.name));
+    Some(match remap {
+        Some(remap) => profile.remap(&remap),
+        None => profile.clone(),
+    })
+}
+
+fn parse_backend(s: &str) -> Result<Backend, String> {
+    Backend::iter()
+        .find(|backend| backend.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("expected one of {:?}", Backend::iter().collect::<Vec<_>>()))
+}
+
+fn parse_strategy(s: &str) -> Result<Strategy, String> {
+    Strategy::iter()
+        .find(|strategy| strategy.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| {
+            format!(
+                "expected one of {:?}",
+                Strategy::iter()
+                    .map(|strategy| strategy.as_ref().to_string())
+                    .collect::<Vec<_>>()
+            )
+        })
+}
+
+fn parse_split(s: &str) -> Result<Split, String> {
+    Split::iter()
+        .find(|split| split.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("expected one of {:?}", Split::iter().collect::<Vec<_>>()))
+}
+
+fn parse_voice(s: &str) -> Result<Voice, String> {
+    Voice::iter()
+        .find(|voice| voice.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("expected one of {:?}", Voice::iter().collect::<Vec<_>>()))
+}
+
+fn parse_percussion(s: &str) -> Result<Percussion, String> {
+    Percussion::iter()
+        .find(|percussion| percussion.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| {
+            format!(
+                "expected one of {:?}",
+                Percussion::iter().collect::<Vec<_>>()
+            )
+        })
+}
+
+fn parse_fold(s: &str) -> Result<Fold, String> {
+    Fold::iter()
+        .find(|fold| fold.as_ref().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("expected one of {:?}", Fold::iter().collect::<Vec<_>>()))
+}
+
+fn parse_start(s: &str) -> Result<usize, String> {
+    parse_time(s).ok_or_else(|| "expected mm:ss or seconds".to_string())
+}
+
+fn parse_start_bar(s: &str) -> Result<(u32, u32), String> {
+    parse_bar(s).ok_or_else(|| "expected bar or bar.beat, counted from 1".to_string())
+}
+
+fn format_time(micros: usize) -> String {
+    format!("{:02}:{:02}", micros / 60000000, micros / 1000000 % 60)
+}
+
+impl Cli {
+    pub fn run(self, profiles: &Profiles) -> ExitCode {
+        match self.command {
+            Command::Play {
+                song,
+                speed,
+                delay,
+                backend,
+                hold,
+                hold_cap,
+                spin,
+                timing,
+                start,
+                bar,
+                r#loop,
+                times,
+                count_in,
+                slow_down,
+            } => {
+                let midi = match song.load(profiles) {
+                    Ok(midi) => midi,
+                    Err(code) => return code,
+                };
+                let player = Player::with_midi(midi);
+                player.control.set_speed(speed);
+                player.settings.store(Settings {
+                    sustain: if hold {
+                        Sustain::Hold { cap: hold_cap }
+                    } else {
+                        Sustain::Tap
+                    },
+                    spin,
+                });
+                sleep(Duration::from_secs_f32(delay.max(0.0)));
+                if let [a, b] = r#loop[..] {
+                    let region = LoopRegion::new(a, b).map(|region| LoopRegion {
+                        times,
+                        count_in,
+                        slow_down: slow_down / 100.0,
+                        ..region
+                    });
+                    if region.is_some_and(|region| !region.has_notes(&player.midi.count.read())) {
+                        eprintln!("no notes between A and B, the passage is not looped");
+                    }
+                    player.region.store(region);
+                }
+                if let Some(micros) = start {
+                    player.control.seek_time(micros);
+                }
+                if let Some((bar, beat)) = bar {
+                    player.control.seek_bar(bar, beat);
+                }
+                player.control.play();
+                let recorder = Recorder::default();
+                let system = (backend == Backend::System).then(|| backend.open());
+                let output: &dyn KeyOutput = system.as_deref().unwrap_or(&recorder);
+                player.playback(song.offset, output);
+                player.control.stop();
+                if backend == Backend::Record {
+                    let keys = recorder.pressed();
+                    println!(
+                        "{}",
+                        keys.iter()
+                            .map(|vk| vk.as_ref())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    );
+                }
+                for (index, note) in player.dropped.read().iter() {
+                    eprintln!("dropped note {note} at event {index}");
+                }
+                if timing {
+                    let lateness = player.lateness.read();
+                    println!(
+                        "lateness of {} chords: mean {}µs, p99 {}µs, max {}µs",
+                        lateness.len(),
+                        lateness.mean() / 1000,
+                        lateness.percentile(99.0) / 1000,
+                        lateness.max() / 1000
+                    );
+                }
+            }
+            Command::Info { song } => {
+                let midi = match song.load(profiles) {
+                    Ok(midi) => midi,
+                    Err(code) => return code,
+                };
+                let analysis = midi.analyze(song.offset);
+                println!("name: {}", midi.name.read().as_deref().unwrap_or_default());
+                for (enable, index, name) in midi.track_num.read().iter() {
+                    let mark = if *enable { '*' } else { ' ' };
+                    let name = if midi.is_percussion(*index) {
+                        format!("{name} (percussion)")
+                    } else {
+                        name.clone()
+                    };
+                    match analysis.tracks.iter().find(|(i, _, _)| i == index) {
+                        Some((_, _, stat)) => println!(
+                            "{mark} track {index}: {name} ({} notes, {:.2}%)",
+                            stat.total,
+                            stat.rate() * 100.0
+                        ),
+                        None => println!("{mark} track {index}: {name}"),
+                    }
+                }
+                println!("notes: {}", midi.events.read().len());
+                println!(
+                    "length: {}",
+                    format_time(midi.count.read().last().copied().unwrap_or_default())
+                );
+                let tempo = midi.tempo.read();
+                if let Division::Metrical(_) = tempo.division() {
+                    let changes = tempo
+                        .changes()
+                        .iter()
+                        .map(|change| {
+                            format!(
+                                "{:.0} BPM at {}",
+                                change.bpm(),
+                                format_time(change.micros as usize)
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    println!("tempo: {}", changes.join(", "));
+                    let meters = tempo
+                        .meters()
+                        .iter()
+                        .map(|meter| {
+                            format!(
+                                "{}/{} at bar {}",
+                                meter.numerator, meter.denominator, meter.bar
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    println!("time signature: {}", meters.join(", "));
+                }
+                drop(tempo);
+                println!("hit rate: {:.2}%", midi.detect(song.offset) * 100.0);
+                println!(
+                    "above range: {}, below range: {}",
+                    analysis.above, analysis.below
+                );
+                if !analysis.missing.is_empty() {
+                    let missing = analysis
+                        .missing
+                        .iter()
+                        .map(|(note, count)| format!("{} x{}", note_name(*note), count))
+                        .collect::<Vec<_>>();
+                    println!("unplayable: {}", missing.join(", "));
+                }
+                if let Some((offset, rate)) = midi.auto_tune(-24..=24).first() {
+                    println!("best offset: {:+} ({:.2}%)", offset, rate * 100.0);
+                }
+            }
+            Command::Convert { song, name, bars } => {
+                let midi = match song.load(profiles) {
+                    Ok(midi) => midi,
+                    Err(code) => return code,
+                };
+                let name = name.unwrap_or_else(|| midi.name.read().clone().unwrap_or_default());
+                if let Err(e) = midi.write_sheet(&name, song.offset, bars) {
+                    eprintln!("failed to write sheets: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            Command::Profiles => {
+                for profile in profiles.iter() {
+                    let (low, high) = profile.bounds().unwrap_or_default();
+                    println!(
+                        "{}: {} notes, {}..={}",
+                        profile.name,
+                        profile.playable().count(),
+                        low,
+                        high
+                    );
+                }
+            }
+            Command::Check { song, min } => {
+                let midi = match song.load(profiles) {
+                    Ok(midi) => midi,
+                    Err(code) => return code,
+                };
+                let rate = midi.detect(song.offset) * 100.0;
+                println!("hit rate: {rate:.2}%");
+                if rate < min {
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::midi::State;
+
+/// What the playback worker is told to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub state: State,
+    pub speed: f32,
+    /// Index of the event being played
+    pub position: usize,
+    /// Where to jump to
+    pub seek: Option<Seek>,
+}
+
+/// A place in the song to jump to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Seek {
+    /// Index of an event
+    Event(usize),
+    /// Microseconds from the start of the song
+    Time(usize),
+    /// A beat of a bar, both counted from 1
+    Bar { bar: u32, beat: u32 },
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            state: State::Stop,
+            speed: 1.0,
+            position: 0,
+            seek: None,
+        }
+    }
+}
+
+/// Commands for the playback worker, which blocks on them instead of polling
+#[derive(Debug, Default)]
+pub struct Controller {
+    status: Mutex<Status>,
+    changed: Condvar,
+    /// Set with the lock held whenever a command was not taken by the worker yet
+    pending: AtomicBool,
+}
+
+impl Controller {
+    fn command(&self, f: impl FnOnce(&mut Status)) {
+        let mut status = self.status.lock();
+        f(&mut status);
+        self.pending.store(true, Ordering::Release);
+        self.changed.notify_all();
+    }
+
+    pub fn play(&self) {
+        self.command(|status| status.state = State::Playing);
+    }
+
+    pub fn pause(&self) {
+        self.command(|status| {
+            if status.state == State::Playing {
+                status.state = State::Pause;
+            }
+        });
+    }
+
+    pub fn resume(&self) {
+        self.command(|status| {
+            if status.state == State::Pause {
+                status.state = State::Playing;
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.command(|status| status.state = State::Stop);
+    }
+
+    pub fn seek(&self, position: usize) {
+        self.command(|status| status.seek = Some(Seek::Event(position)));
+    }
+
+    pub fn seek_time(&self, micros: usize) {
+        self.command(|status| status.seek = Some(Seek::Time(micros)));
+    }
+
+    pub fn seek_bar(&self, bar: u32, beat: u32) {
+        self.command(|status| status.seek = Some(Seek::Bar { bar, beat }));
+    }
+
+    pub fn set_speed(&self, speed: f32) {
+        self.command(|status| status.speed = speed.max(0.01));
+    }
+
+    pub fn state(&self) -> State {
+        self.status.lock().state
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.status.lock().speed
+    }
+
+    pub fn position(&self) -> usize {
+        self.status.lock().position
+    }
+
+    /// Called by the worker when it reaches another event
+    pub fn set_position(&self, position: usize) {
+        self.status.lock().position = position;
+    }
+
+    /// Whether a command is waiting for the worker
+    #[inline]
+    pub fn pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// The status the worker should follow, the seek is only taken once
+    pub fn take(&self) -> Status {
+        let mut status = self.status.lock();
+        self.pending.store(false, Ordering::Release);
+        let taken = *status;
+        status.seek = None;
+        taken
+    }
+
+    /// Blocks until a command arrives or `deadline` passes, returns whether a command arrived
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let mut status = self.status.lock();
+        while !self.pending() {
+            if self.changed.wait_until(&mut status, deadline).timed_out() {
+                break;
+            }
+        }
+        self.pending()
+    }
+
+    /// Blocks until a command arrives
+    pub fn wait(&self) {
+        let mut status = self.status.lock();
+        while !self.pending() {
+            self.changed.wait(&mut status);
+        }
+    }
+}
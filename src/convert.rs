@@ -1,64 +1,14 @@
-use std::fs;
+use std::{fs, io};
 
 use rfd::MessageButtons;
 
-use crate::midi::{is_playing, Event, Midi};
+use crate::midi::{Event, Midi};
 use crate::POOL;
 
 impl Midi {
-    pub fn convert_from_midi(self, name: String) {
-        if is_playing() {
-            return;
-        }
+    pub fn convert_from_midi(self, name: String, offset: i32, bars: bool) {
         POOL.spawn(move || {
-            let mut res = String::new();
-            let mut cache = String::new();
-            let mut count = 0;
-
-            self.events.read().iter().for_each(|event| {
-                if event.delay != 0.0 {
-                    if !cache.is_empty() {
-                        let s = if count > 1 {
-                            format!("[{cache}] ")
-                        } else {
-                            format!("{cache} ")
-                        };
-                        res.push_str(&s);
-                    }
-                    cache = format!("{} ", event.get_key());
-                    count = 1;
-                } else {
-                    cache.push_str(&format!("{} ", event.get_key()));
-                    count += 1;
-                }
-            });
-
-            let phone = res
-                .replace('q', "+1")
-                .replace('w', "+2")
-                .replace('e', "+3")
-                .replace('r', "+4")
-                .replace('t', "+5")
-                .replace('y', "+6")
-                .replace('u', "+7")
-                .replace('a', "1")
-                .replace('s', "2")
-                .replace('d', "3")
-                .replace('f', "4")
-                .replace('g', "5")
-                .replace('h', "6")
-                .replace('j', "7")
-                .replace('z', "-1")
-                .replace('x', "-2")
-                .replace('c', "-3")
-                .replace('v', "-4")
-                .replace('b', "-5")
-                .replace('n', "-6")
-                .replace('m', "-7");
-            if let (Ok(_), Ok(_)) = (
-                fs::write(format!("{}.txt", name), res.to_uppercase().as_bytes()),
-                fs::write(format!("phone-{}.txt", name), phone.as_bytes()),
-            ) {
+            if self.write_sheet(&name, offset, bars).is_ok() {
                 rfd::MessageDialog::new()
                     .set_description("转换成功\n请查看当前目录下的txt文本文件")
                     .set_buttons(MessageButtons::Ok)
@@ -71,54 +21,85 @@ impl Midi {
             }
         });
     }
-}
 
-impl Event {
-    fn get_key(&self) -> &'static str {
-        match self.press {
-            24 => "z",
-            26 => "x",
-            28 => "c",
-            29 => "v",
-            31 => "b",
-            33 => "n",
-            35 => "m",
-            36 => "z",
-            38 => "x",
-            40 => "c",
-            41 => "v",
-            43 => "b",
-            45 => "n",
-            47 => "m",
-            48 => "z",
-            50 => "x",
-            52 => "c",
-            53 => "v",
-            55 => "b",
-            57 => "n",
-            59 => "m",
-            60 => "a",
-            62 => "s",
-            64 => "d",
-            65 => "f",
-            67 => "g",
-            69 => "h",
-            71 => "j",
-            72 => "q",
-            74 => "w",
-            76 => "e",
-            77 => "r",
-            79 => "t",
-            81 => "y",
-            83 => "u",
-            84 => "q",
-            86 => "w",
-            88 => "e",
-            89 => "r",
-            91 => "t",
-            93 => "y",
-            95 => "u",
-            _ => "",
-        }
+    /// Writes `{name}.txt` and `phone-{name}.txt` into the current directory
+    pub fn write_sheet(&self, name: &str, offset: i32, bars: bool) -> io::Result<()> {
+        let (sheet, phone) = self.to_sheet(offset, bars);
+        fs::write(format!("{}.txt", name), sheet.as_bytes())?;
+        fs::write(format!("phone-{}.txt", name), phone.as_bytes())
+    }
+
+    /// 1. The keyboard sheet
+    /// 2. The numbered sheet
+    ///
+    /// Notes are shifted by `offset` as they are when played,
+    /// every bar is on its own line with `bars`
+    pub fn to_sheet(&self, offset: i32, bars: bool) -> (String, String) {
+        let profile = self.profile.read();
+        let drums = self.drums.read();
+        let events = self.events.read();
+        let fold = self.fold.load();
+        let sheet = Self::sheet(&events, bars, |event| {
+            event
+                .key(offset, &profile, &drums, fold)
+                .map(|vk| vk.as_ref().to_uppercase())
+                .unwrap_or_default()
+        });
+        let phone = Self::sheet(&events, bars, |event| {
+            event
+                .key(offset, &profile, &drums, fold)
+                .map(|vk| {
+                    if event.percussion {
+                        drums.label(vk)
+                    } else {
+                        profile.label(vk)
+                    }
+                })
+                .unwrap_or_default()
+        });
+        (sheet, phone)
+    }
+
+    /// Notes pressed at the same instant are grouped in brackets,
+    /// every bar is on its own line with `bars`
+    fn sheet(events: &[Event], bars: bool, text: impl Fn(&Event) -> String) -> String {
+        let mut res = String::new();
+        let mut cache = String::new();
+        let mut count = 0;
+        let mut bar = events
+            .first()
+            .and_then(|event| event.bar)
+            .map(|position| position.bar);
+        let mut flush = |cache: &str, count: usize, new_bar: bool| {
+            if new_bar && !res.is_empty() {
+                res.push('\n');
+            }
+            if !cache.is_empty() {
+                let s = if count > 1 {
+                    format!("[{cache}] ")
+                } else {
+                    format!("{cache} ")
+                };
+                res.push_str(&s);
+            }
+        };
+
+        events.iter().for_each(|event| {
+            if event.delay != 0.0 {
+                flush(&cache, count, false);
+                let current = event.bar.map(|position| position.bar);
+                if bars && bar.is_some() && current != bar {
+                    flush("", 0, true);
+                }
+                bar = current;
+                cache = format!("{} ", text(event));
+                count = 1;
+            } else {
+                cache.push_str(&format!("{} ", text(event)));
+                count += 1;
+            }
+        });
+        flush(&cache, count, false);
+        res
     }
 }
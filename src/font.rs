@@ -4,12 +4,19 @@ use font_kit::source::SystemSource;
 pub fn load_fonts(ctx: &egui::Context) {
     let sys = SystemSource::new();
     let font_name = "Microsoft YaHei UI".to_string();
-    let font = sys.select_family_by_name(&font_name).unwrap().fonts()[2]
-        .load()
-        .unwrap()
-        .copy_font_data()
-        .unwrap()
-        .to_vec();
+    let Ok(family) = sys.select_family_by_name(&font_name) else {
+        return;
+    };
+    let fonts = family.fonts();
+    let Some(font) = fonts
+        .get(2)
+        .or(fonts.first())
+        .and_then(|handle| handle.load().ok())
+        .and_then(|font| font.copy_font_data())
+        .map(|data| data.to_vec())
+    else {
+        return;
+    };
     let mut font_defs = egui::FontDefinitions::default();
     font_defs.font_data.insert(
         font_name.to_string(),
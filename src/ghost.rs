@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use crate::midi::{retain, Event};
+
+/// Leaves out soft notes, ghost notes and grace notes only spam keys in game
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GhostFilter {
+    /// Notes softer than this are always left out
+    pub min_velocity: u8,
+    /// Notes softer than this are ghost notes, which are thinned out
+    pub ghost_velocity: u8,
+    /// Milliseconds after a kept note in which ghost notes are left out
+    pub min_gap: u32,
+}
+
+impl GhostFilter {
+    /// The events that are not filtered out
+    pub fn apply(self, events: Vec<Event>) -> Vec<Event> {
+        if self.min_velocity == 0 && (self.ghost_velocity == 0 || self.min_gap == 0) {
+            return events;
+        }
+        let gap = self.min_gap as f32 * 1000.0;
+        let mut time = 0.0;
+        let mut kept = f32::NEG_INFINITY;
+        let keep = events
+            .iter()
+            .map(|event| {
+                time += event.delay;
+                let ghost = event.velocity < self.ghost_velocity;
+                if event.velocity < self.min_velocity || (ghost && time - kept < gap) {
+                    return false;
+                }
+                kept = time;
+                true
+            })
+            .collect::<Vec<_>>();
+        retain(events, keep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Notes of `(velocity, delay in milliseconds)`
+    fn notes(notes: &[(u8, f32)]) -> Vec<Event> {
+        notes
+            .iter()
+            .enumerate()
+            .map(|(i, (velocity, delay))| Event {
+                velocity: *velocity,
+                ..Event::note(60 + i as i32, delay * 1000.0)
+            })
+            .collect()
+    }
+
+    fn kept(filter: GhostFilter, events: &[(u8, f32)]) -> Vec<(i32, f32)> {
+        filter
+            .apply(notes(events))
+            .iter()
+            .map(|e| (e.press, e.delay / 1000.0))
+            .collect()
+    }
+
+    #[test]
+    fn disabled() {
+        let events = [(1, 0.0), (1, 1.0)];
+        assert_eq!(kept(GhostFilter::default(), &events).len(), 2);
+        let gap_only = GhostFilter {
+            min_gap: 100,
+            ..Default::default()
+        };
+        assert_eq!(kept(gap_only, &events).len(), 2);
+    }
+
+    #[test]
+    fn soft_notes_are_dropped() {
+        let filter = GhostFilter {
+            min_velocity: 40,
+            ..Default::default()
+        };
+        let events = [(100, 10.0), (20, 10.0), (39, 10.0), (40, 10.0)];
+        // The delays of the dropped notes go to the next one
+        assert_eq!(kept(filter, &events), [(60, 10.0), (63, 30.0)]);
+    }
+
+    #[test]
+    fn ghost_notes_close_to_a_note_are_dropped() {
+        let filter = GhostFilter {
+            ghost_velocity: 50,
+            min_gap: 100,
+            ..Default::default()
+        };
+        let events = [(100, 0.0), (30, 50.0), (30, 60.0), (30, 50.0), (100, 10.0)];
+        // The second ghost note is 110ms after the first note, the last ghost note
+        // is only 50ms after it, loud notes are always kept
+        assert_eq!(kept(filter, &events), [(60, 0.0), (62, 110.0), (64, 60.0)]);
+    }
+}
@@ -1,20 +1,39 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::path::Path;
+use std::process::ExitCode;
 use std::sync::Arc;
 
+use clap::Parser;
 use eframe::egui::{IconData, Vec2, ViewportBuilder};
 use eframe::NativeOptions;
 
-use lyred::maps::MAP;
+use lyred::profile::Profiles;
 use lyred::ui::play::Play;
 
-fn main() {
-    run();
+use crate::cli::Cli;
+
+mod cli;
+
+fn main() -> ExitCode {
+    let mut profiles = Profiles::builtin();
+    for error in profiles.load_dir("profiles") {
+        eprintln!("failed to load profile {error}");
+    }
+    if std::env::args_os().len() > 1 {
+        #[cfg(windows)]
+        unsafe {
+            use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+            AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+        return Cli::parse().run(&profiles);
+    }
+    run(profiles);
+    ExitCode::SUCCESS
 }
 
 #[inline]
-fn run() {
+fn run(profiles: Profiles) {
     let image = image::load_from_memory(include_bytes!("../resources/lyre.ico")).unwrap();
     let viewport = ViewportBuilder {
         resizable: Some(false),
@@ -35,17 +54,28 @@ fn run() {
         "Lyred",
         options,
         Box::new(|cc| {
-            let mut play = Play::new(cc);
+            let mut play = Play::new(cc, profiles);
             if let Ok(file) = std::fs::read_to_string("config.ron") {
                 play.config = ron::from_str(&file).unwrap_or_default();
+                play.config.migrate();
                 let dir = play.config.midi_dir.0.read();
                 if !dir.is_empty() {
-                    play.midi.get_midis_path(Path::new(dir.as_str()));
+                    play.player.get_midis_path(Path::new(dir.as_str()));
                 }
-                unsafe {
-                    MAP = play.config.map;
+                drop(dir);
+                play.output = play.config.backend.open();
+                play.player.midi.chord.store(play.config.chord);
+                play.player.midi.ghost.store(play.config.ghost);
+                play.player.midi.melody.store(play.config.melody);
+                play.player.midi.fold.store(play.config.fold);
+                play.player.midi.split.store(play.config.split);
+                play.player.midi.percussion.store(play.config.percussion);
+                if let Some(drums) = play.profiles.get(&play.config.drums) {
+                    *play.player.midi.drums.write() = drums.clone();
                 }
+                *play.player.mixes.write() = std::mem::take(&mut play.config.mixes);
             }
+            play.apply_profile();
             Ok(Box::new(play))
         }),
     )
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumIter};
+
+use crate::midi::{retain, Event};
+
+/// Which notes are taken as one voice when only the top one is kept
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, EnumIter, AsRefStr,
+)]
+pub enum Voice {
+    /// Keep every note
+    #[default]
+    Off,
+    /// Keep the top note of each track
+    Track,
+    /// Keep the top note of the tracks played together
+    Merged,
+}
+
+/// Extracts the melody for instruments that play one note at a time.
+/// Of the notes starting within `window` milliseconds the highest is kept,
+/// a lower note starting while it sounds is left out and a higher one cuts it short.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Skyline {
+    pub voice: Voice,
+    pub window: u32,
+}
+
+/// The note on top of a voice
+#[derive(Debug, Clone, Copy)]
+struct Top {
+    /// Microseconds from the start of the song to the first note starting with it
+    onset: f32,
+    time: f32,
+    end: f32,
+    press: i32,
+    index: usize,
+}
+
+impl Skyline {
+    /// The top notes of `events`, percussion is kept as it is
+    pub fn apply(self, mut events: Vec<Event>) -> Vec<Event> {
+        if self.voice == Voice::Off {
+            return events;
+        }
+        let window = self.window as f32 * 1000.0;
+        let mut keep = vec![true; events.len()];
+        let mut tops: Vec<(usize, Top)> = vec![];
+        let mut time = 0.0;
+        for index in 0..events.len() {
+            let event = events[index];
+            time += event.delay;
+            if event.percussion {
+                continue;
+            }
+            let voice = match self.voice {
+                Voice::Track => event.track,
+                _ => 0,
+            };
+            let note = Top {
+                onset: time,
+                time,
+                end: time + event.duration,
+                press: event.press,
+                index,
+            };
+            let Some((_, top)) = tops.iter_mut().find(|(v, _)| *v == voice) else {
+                tops.push((voice, note));
+                continue;
+            };
+            if time - top.onset <= window {
+                // Starting together with the top note
+                if event.press > top.press {
+                    keep[top.index] = false;
+                    *top = Top {
+                        onset: top.onset,
+                        ..note
+                    };
+                } else {
+                    keep[index] = false;
+                }
+            } else if time < top.end && event.press < top.press {
+                keep[index] = false;
+            } else {
+                if time < top.end {
+                    events[top.index].duration = time - top.time;
+                }
+                *top = note;
+            }
+        }
+        retain(events, keep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Notes of `(track, press, delay, duration)` in milliseconds
+    fn notes(notes: &[(usize, i32, f32, f32)]) -> Vec<Event> {
+        notes
+            .iter()
+            .map(|(track, press, delay, duration)| Event {
+                track: *track,
+                duration: duration * 1000.0,
+                ..Event::note(*press, delay * 1000.0)
+            })
+            .collect()
+    }
+
+    fn top(voice: Voice, window: u32, events: &[(usize, i32, f32, f32)]) -> Vec<(i32, f32, f32)> {
+        Skyline { voice, window }
+            .apply(notes(events))
+            .iter()
+            .map(|e| (e.press, e.delay / 1000.0, e.duration / 1000.0))
+            .collect()
+    }
+
+    #[test]
+    fn off() {
+        let events = [(0, 60, 0.0, 100.0), (0, 64, 0.0, 100.0)];
+        assert_eq!(top(Voice::Off, 0, &events).len(), 2);
+    }
+
+    #[test]
+    fn highest_of_a_chord() {
+        let events = [
+            (0, 60, 10.0, 100.0),
+            (0, 67, 0.0, 100.0),
+            (0, 64, 0.0, 100.0),
+        ];
+        assert_eq!(top(Voice::Merged, 0, &events), [(67, 10.0, 100.0)]);
+    }
+
+    #[test]
+    fn lower_notes_under_a_held_note_are_left_out() {
+        // A long melody note over a moving bass, then a note after it ends
+        let events = [
+            (0, 72, 0.0, 300.0),
+            (0, 48, 0.0, 100.0),
+            (0, 50, 100.0, 100.0),
+            (0, 52, 100.0, 100.0),
+            (0, 53, 100.0, 100.0),
+        ];
+        assert_eq!(
+            top(Voice::Merged, 0, &events),
+            [(72, 0.0, 300.0), (53, 300.0, 100.0)]
+        );
+    }
+
+    #[test]
+    fn higher_notes_cut_the_held_note_short() {
+        let events = [
+            (0, 60, 0.0, 300.0),
+            (0, 64, 100.0, 100.0),
+            (0, 60, 50.0, 100.0),
+        ];
+        assert_eq!(
+            top(Voice::Merged, 0, &events),
+            [(60, 0.0, 100.0), (64, 100.0, 100.0)]
+        );
+        // The same note again is played again
+        let events = [(0, 60, 0.0, 300.0), (0, 60, 100.0, 100.0)];
+        assert_eq!(
+            top(Voice::Merged, 0, &events),
+            [(60, 0.0, 100.0), (60, 100.0, 100.0)]
+        );
+    }
+
+    #[test]
+    fn window() {
+        // A grace note just before the melody note
+        let events = [
+            (0, 62, 0.0, 20.0),
+            (0, 60, 20.0, 100.0),
+            (0, 67, 10.0, 100.0),
+        ];
+        assert_eq!(top(Voice::Merged, 30, &events), [(67, 30.0, 100.0)]);
+        assert_eq!(
+            top(Voice::Merged, 0, &events),
+            [(62, 0.0, 20.0), (60, 20.0, 10.0), (67, 10.0, 100.0)]
+        );
+    }
+
+    #[test]
+    fn each_track_has_its_own_top() {
+        let events = [
+            (0, 72, 0.0, 300.0),
+            (1, 48, 0.0, 300.0),
+            (1, 50, 100.0, 100.0),
+        ];
+        assert_eq!(
+            top(Voice::Track, 0, &events),
+            [(72, 0.0, 300.0), (48, 0.0, 100.0), (50, 100.0, 100.0)]
+        );
+        assert_eq!(top(Voice::Merged, 0, &events), [(72, 0.0, 300.0)]);
+    }
+}
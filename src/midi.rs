@@ -1,17 +1,27 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::sync::Arc;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
 
 use crossbeam::atomic::AtomicCell;
-use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use midly::{Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEventKind};
 use parking_lot::RwLock;
-use rand::Rng;
 use rayon::slice::ParallelSliceMut;
-
-use crate::maps::get_map;
-use crate::ui::play::{Mode, PlayMode};
-use crate::{COUNT, LOCAL, POOL, TIME_SHIFT};
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumIter};
+
+use crate::chord::{self, ChordLimit};
+use crate::control::Seek;
+use crate::ghost::GhostFilter;
+use crate::gm;
+use crate::maps::Fold;
+use crate::melody::Skyline;
+use crate::profile::{InstrumentProfile, Profiles, DRUMS};
+use crate::tempo::{BarBeat, TempoMap};
+use crate::util::VKey;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
@@ -20,25 +30,168 @@ pub enum State {
     Pause,
 }
 
-pub static PLAYING: AtomicCell<bool> = AtomicCell::new(false);
-pub static STATE: AtomicCell<State> = AtomicCell::new(State::Stop);
-pub static SPEED: AtomicCell<f32> = AtomicCell::new(1.0);
-pub static CURRENT_MIDI: AtomicCell<usize> = AtomicCell::new(0);
+/// Why a MIDI file could not be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Parse(midly::Error),
+    UnsupportedTiming(Timing),
+    /// The file has no note to play
+    Empty,
+    /// Every note is on the percussion channel, which is skipped
+    OnlyPercussion,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "failed to read the file: {e}"),
+            LoadError::Parse(e) => write!(f, "not a valid MIDI file: {e}"),
+            LoadError::UnsupportedTiming(timing) => write!(f, "unsupported timing {timing:?}"),
+            LoadError::Empty => write!(f, "the file has no notes"),
+            LoadError::OnlyPercussion => {
+                write!(f, "the file only has percussion, which is skipped")
+            }
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<midly::Error> for LoadError {
+    fn from(e: midly::Error) -> Self {
+        LoadError::Parse(e)
+    }
+}
 
-pub fn is_playing() -> bool {
-    !matches!(STATE.load(), State::Stop)
+/// What a tick of the file stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Division {
+    /// Ticks per quarter note, tempo events set how long a quarter note is
+    Metrical(f32),
+    /// Ticks per second, frames per second times subframes, tempo events are ignored
+    Timecode(f32),
+}
+
+/// How long a key stays down once it is pressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Sustain {
+    /// Release the key right after pressing it
+    #[default]
+    Tap,
+    /// Hold the key for the length of the note, at most `cap` milliseconds when set
+    Hold { cap: Option<u32> },
+}
+
+impl Sustain {
+    /// The time in microseconds a note with `duration` is held for,
+    /// `None` when it should be tapped
+    pub fn hold(self, duration: f32) -> Option<f32> {
+        match self {
+            Sustain::Tap => None,
+            Sustain::Hold { .. } if duration <= 0.0 => None,
+            Sustain::Hold { cap: None } => Some(duration),
+            Sustain::Hold { cap: Some(cap) } => Some(duration.min(cap as f32 * 1000.0)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Midi {
     pub name: Arc<RwLock<Option<String>>>,
     pub events: Arc<RwLock<Vec<Event>>>,
-    pub fps: Arc<AtomicCell<f32>>,
+    /// Tempo changes of every track, whether it is merged or not
+    pub tempo: Arc<RwLock<TempoMap>>,
     pub tracks: Arc<RwLock<Vec<Vec<RawEvent>>>>,
     pub track_num: Arc<RwLock<Vec<(bool, usize, String)>>>,
     pub track_keys: Arc<RwLock<Vec<Vec<TrackKey>>>>,
+    /// The mix of every track, reset when a song is read
+    pub mixer: Arc<RwLock<Vec<TrackMix>>>,
+    /// The tracks as they are in the file
+    pub sources: Arc<RwLock<Vec<SourceTrack>>>,
+    pub split: Arc<AtomicCell<Split>>,
+    pub percussion: Arc<AtomicCell<Percussion>>,
+    /// The profile percussion is played with when it is mapped to drums
+    pub drums: Arc<RwLock<InstrumentProfile>>,
     pub hit_rate: Arc<AtomicCell<f32>>,
-    pub midis: Arc<RwLock<Vec<String>>>,
+    pub profile: Arc<RwLock<InstrumentProfile>>,
+    /// Microseconds from the start of the song to each event
+    pub count: Arc<RwLock<Vec<usize>>>,
+    pub chord: Arc<AtomicCell<ChordLimit>>,
+    pub ghost: Arc<AtomicCell<GhostFilter>>,
+    pub melody: Arc<AtomicCell<Skyline>>,
+    /// What is done with the notes the profile has no key for
+    pub fold: Arc<AtomicCell<Fold>>,
+}
+
+/// How the tracks of a file are divided into the tracks that can be selected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, EnumIter, AsRefStr)]
+pub enum Split {
+    /// Every track of the file is one track
+    Off,
+    /// A track with notes on several channels is split by channel
+    #[default]
+    Channel,
+    /// A track is split by channel and by the program its notes are played with
+    Program,
+}
+
+/// What is done with the notes of the General MIDI percussion channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, EnumIter, AsRefStr)]
+pub enum Percussion {
+    /// They are left out
+    #[default]
+    Skip,
+    /// They are played like any other note
+    Melodic,
+    /// They are played with the keys of the drum profile
+    Drums,
+}
+
+/// A track as it is in the file, before it is split
+#[derive(Debug, Clone)]
+pub struct SourceTrack {
+    pub name: String,
+    events: Vec<RawEvent>,
+    keys: Vec<TrackKey>,
+}
+
+/// How a track is mixed into the song
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackMix {
+    /// Semitones added to every note of the track
+    pub transpose: i32,
+    pub mute: bool,
+    /// Only the soloed tracks are played when any track is soloed
+    pub solo: bool,
+    /// Notes played softer than this are left out
+    pub min_velocity: u8,
+}
+
+impl TrackMix {
+    /// Whether the track is heard, `solo` is set when any track is soloed
+    pub fn audible(&self, solo: bool) -> bool {
+        !self.mute && (self.solo || !solo)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -61,298 +214,511 @@ impl Midi {
         Midi {
             name: Arc::new(RwLock::new(None)),
             events: Arc::new(RwLock::new(vec![])),
-            fps: Arc::new(Default::default()),
+            tempo: Arc::new(RwLock::new(TempoMap::default())),
             tracks: Arc::new(RwLock::new(vec![])),
             track_num: Arc::new(RwLock::new(vec![])),
             track_keys: Arc::new(RwLock::new(vec![])),
+            mixer: Arc::new(RwLock::new(vec![])),
+            sources: Arc::new(RwLock::new(vec![])),
+            split: Arc::new(AtomicCell::new(Split::default())),
+            percussion: Arc::new(AtomicCell::new(Percussion::default())),
+            drums: Arc::new(RwLock::new(
+                Profiles::builtin().get(DRUMS).cloned().unwrap_or_default(),
+            )),
             hit_rate: Arc::new(Default::default()),
-            midis: Arc::new(RwLock::new(vec![])),
+            profile: Arc::new(RwLock::new(InstrumentProfile::default())),
+            count: Arc::new(RwLock::new(vec![])),
+            chord: Arc::new(AtomicCell::new(ChordLimit::default())),
+            ghost: Arc::new(AtomicCell::new(GhostFilter::default())),
+            melody: Arc::new(AtomicCell::new(Skyline::default())),
+            fold: Arc::new(AtomicCell::new(Fold::default())),
         }
     }
 
-    fn play<F: Fn(i32)>(&self, offset: i32, f: F) {
-        let events = self.events.read();
-        let mut start_time = Instant::now();
-        let mut input_time = 0.0;
-        let mut i = 0;
-        while i < events.len() {
-            if TIME_SHIFT.load() {
-                TIME_SHIFT.store(false);
-                i = LOCAL.load();
-                input_time = events[i].delay;
-                start_time = Instant::now();
-            } else {
-                LOCAL.store(i);
-            }
-            let e = events[i];
-            i += 1;
-
-            input_time += e.delay / SPEED.load();
-            if let current @ 1.. = (input_time - start_time.elapsed().as_micros() as f32) as u64 {
-                sleep(Duration::from_micros(current));
-            }
-            match STATE.load() {
-                State::Playing => f(e.press + offset),
-                State::Pause => {
-                    while STATE.load() == State::Pause {}
-                    input_time = e.delay;
-                    start_time = Instant::now();
-                    i -= 1;
-                }
-                State::Stop => break,
+    /// Parses the file, keeping every event up to the first broken one of each track
+    /// when it is slightly malformed
+    fn parse(file: &[u8]) -> Result<(Header, Vec<Track<'_>>), midly::Error> {
+        match Smf::parse(file) {
+            Ok(smf) => Ok((smf.header, smf.tracks)),
+            Err(e) => {
+                let (header, tracks) = midly::parse(file).map_err(|_| e)?;
+                let tracks = tracks
+                    .flatten()
+                    .map(|track| track.map_while(Result::ok).collect())
+                    .collect();
+                Ok((header, tracks))
             }
         }
     }
 
-    pub fn init(self) {
-        POOL.spawn(move || {
-            if let Some(ref path) = rfd::FileDialog::new()
-                .add_filter("MIDI File", &["mid"])
-                .pick_file()
-            {
-                self.read_midi(path);
-            }
-        });
-    }
-
-    pub fn read_midi(&self, path: impl AsRef<Path>) {
+    /// Reads and merges every track of the file, the song is left as it was on errors
+    pub fn read_midi(&self, path: impl AsRef<Path>) -> Result<(), LoadError> {
         let path = path.as_ref();
-        let file = std::fs::read(path).unwrap_or_default();
-        let Ok(smf) = Smf::parse(&file) else {
-            return;
+        let file = std::fs::read(path)?;
+        let (header, tracks) = Self::parse(&file)?;
+        let division = match header.timing {
+            Timing::Metrical(tpq) if tpq.as_int() > 0 => Division::Metrical(tpq.as_int() as f32),
+            Timing::Timecode(fps, subframes) if subframes > 0 => {
+                Division::Timecode(fps.as_f32() * subframes as f32)
+            }
+            timing => return Err(LoadError::UnsupportedTiming(timing)),
         };
-        self.name.write().replace(
-            path.file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .into_owned(),
-        );
-        self.fps.store(match smf.header.timing {
-            Timing::Metrical(fps) => fps.as_int() as f32,
-            Timing::Timecode(fps, timing) => timing as f32 * fps.as_f32(),
-        });
-        let track_len = smf.tracks.len();
-
-        let mut track_keys = Vec::with_capacity(track_len);
-        let mut track_num = Vec::with_capacity(track_len);
-        *self.tracks.write() = smf
-            .tracks
+        let mut tempos = vec![];
+        let mut meters = vec![];
+        let sources = tracks
             .into_iter()
             .enumerate()
             .map(|(index, track)| {
                 let mut tick = 0;
                 let mut track_name = String::from("Untitle");
                 let mut keys = vec![];
-                let events = track
-                    .into_iter()
-                    .map(|e| {
-                        tick += e.delta.as_int();
-                        let event = match e.kind {
-                            TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
-                                track_name = String::from_utf8_lossy(name).to_string();
-                                ValidEvent::Other
-                            }
-                            TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
-                                ValidEvent::Tempo(t.as_int())
-                            }
-                            TrackEventKind::Meta(MetaMessage::KeySignature(key, _)) => {
-                                keys.push(TrackKey {
-                                    tick,
-                                    key: key as i32,
-                                    backup: key as i32,
-                                    real: 0,
-                                });
-                                ValidEvent::Other
+                let mut pending: HashMap<(u8, u8), VecDeque<usize>> = HashMap::new();
+                let mut programs = [0; 16];
+                let mut events = Vec::with_capacity(track.len());
+                for e in track {
+                    tick += e.delta.as_int();
+                    let event = match e.kind {
+                        TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                            track_name = String::from_utf8_lossy(name).to_string();
+                            ValidEvent::Other
+                        }
+                        TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                            tempos.push((tick, t.as_int()));
+                            ValidEvent::Other
+                        }
+                        TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, power, ..)) => {
+                            meters.push((tick, numerator, power));
+                            ValidEvent::Other
+                        }
+                        TrackEventKind::Meta(MetaMessage::KeySignature(key, _)) => {
+                            keys.push(TrackKey {
+                                tick,
+                                key: key as i32,
+                                backup: key as i32,
+                                real: 0,
+                            });
+                            ValidEvent::Other
+                        }
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOn { key, vel },
+                        } if vel > 0 => {
+                            pending
+                                .entry((channel.as_int(), key.as_int()))
+                                .or_default()
+                                .push_back(events.len());
+                            ValidEvent::Note {
+                                key: key.as_int() as i32,
+                                len: 0,
+                                vel: vel.as_int(),
+                                channel: channel.as_int(),
+                                program: programs[channel.as_int() as usize],
                             }
-                            TrackEventKind::Midi {
-                                message: MidiMessage::NoteOn { key, vel },
-                                ..
-                            } => {
-                                if vel > 0 {
-                                    ValidEvent::Note(key.as_int() as i32)
-                                } else {
-                                    ValidEvent::Other
+                        }
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::ProgramChange { program },
+                        } => {
+                            programs[channel.as_int() as usize] = program.as_int();
+                            ValidEvent::Other
+                        }
+                        TrackEventKind::Midi {
+                            channel,
+                            message:
+                                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                        } => {
+                            // A zero velocity NoteOn is a NoteOff as well,
+                            // it releases the earliest note still sounding
+                            if let Some(start) = pending
+                                .get_mut(&(channel.as_int(), key.as_int()))
+                                .and_then(|open| open.pop_front())
+                            {
+                                let RawEvent {
+                                    event, tick: from, ..
+                                } = &mut events[start];
+                                if let ValidEvent::Note { len, .. } = event {
+                                    *len = tick - *from;
                                 }
                             }
-                            _ => ValidEvent::Other,
-                        };
-                        RawEvent { event, tick }
-                    })
-                    .collect::<Vec<_>>();
-                track_keys.push(keys);
-                track_num.push((true, index, track_name));
-                events
+                            ValidEvent::Other
+                        }
+                        _ => ValidEvent::Other,
+                    };
+                    events.push(RawEvent {
+                        event,
+                        tick,
+                        track: index,
+                    });
+                }
+                SourceTrack {
+                    name: track_name,
+                    events,
+                    keys,
+                }
             })
             .collect::<Vec<_>>();
+        let mut notes = sources
+            .iter()
+            .flat_map(|source| &source.events)
+            .filter_map(|e| match e.event {
+                ValidEvent::Note { channel, .. } => Some(channel),
+                ValidEvent::Other => None,
+            })
+            .peekable();
+        if notes.peek().is_none() {
+            return Err(LoadError::Empty);
+        }
+        if self.percussion.load() == Percussion::Skip
+            && notes.all(|channel| channel == gm::PERCUSSION_CHANNEL)
+        {
+            return Err(LoadError::OnlyPercussion);
+        }
 
+        self.name.write().replace(
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        );
+        *self.tempo.write() = TempoMap::new(division, tempos, meters);
+        *self.sources.write() = sources;
+        self.split_tracks();
+        Ok(())
+    }
+
+    /// Splits the tracks of the file again when `split` is another way
+    pub fn set_split(&self, split: Split) {
+        if self.split.swap(split) != split && !self.sources.read().is_empty() {
+            self.split_tracks();
+        }
+    }
+
+    /// Divides the tracks of the file into the tracks that can be selected,
+    /// every one of them is enabled and mixed as is
+    fn split_tracks(&self) {
+        let split = self.split.load();
+        let sources = self.sources.read();
+        let mut tracks = vec![];
+        let mut track_keys = vec![];
+        let mut track_num = vec![];
+        for source in sources.iter() {
+            // Notes by channel, and program when split by program, in the order they appear
+            let mut groups: Vec<((u8, u8), Vec<RawEvent>)> = vec![];
+            for event in &source.events {
+                let ValidEvent::Note {
+                    channel, program, ..
+                } = event.event
+                else {
+                    continue;
+                };
+                let group = match split {
+                    Split::Off => (0, 0),
+                    Split::Channel => (channel, 0),
+                    Split::Program => (channel, program),
+                };
+                match groups.iter_mut().find(|(key, _)| *key == group) {
+                    Some((_, events)) => events.push(*event),
+                    None => groups.push((group, vec![*event])),
+                }
+            }
+            if groups.is_empty() {
+                groups.push(((0, 0), vec![]));
+            }
+            let single = groups.len() == 1;
+            for (_, mut events) in groups {
+                let index = tracks.len();
+                let name = match events.first().map(|e| e.event) {
+                    Some(ValidEvent::Note {
+                        channel, program, ..
+                    }) if !single => {
+                        let name =
+                            format!("Ch{} {}", channel + 1, gm::instrument(channel, program));
+                        if source.name == "Untitle" {
+                            name
+                        } else {
+                            format!("{} / {name}", source.name)
+                        }
+                    }
+                    Some(ValidEvent::Note {
+                        channel, program, ..
+                    }) if source.name == "Untitle" => gm::instrument(channel, program).to_string(),
+                    _ => source.name.clone(),
+                };
+                events.iter_mut().for_each(|event| event.track = index);
+                tracks.push(events);
+                track_keys.push(source.keys.clone());
+                track_num.push((true, index, name));
+            }
+        }
+        drop(sources);
+        let track_len = tracks.len();
+        *self.tracks.write() = tracks;
         *self.track_keys.write() = track_keys;
         *self.track_num.write() = track_num;
+        *self.mixer.write() = vec![TrackMix::default(); track_len];
         self.merge_tracks(&(0..track_len).collect::<Vec<_>>(), 0);
     }
 
     pub fn merge_tracks(&self, indices: &[usize], offset: i32) {
-        const DEFAULT_TEMPO_MPQ: u32 = 500000;
-
         let mut current = vec![];
         let mut tracks = self.tracks.read().to_vec();
         let track_keys = self.track_keys.read();
+        let mixer = self.mixer.read();
+        let solo = mixer.iter().any(|mix| mix.solo);
         for (index, events) in tracks.iter_mut().enumerate() {
-            let mut keys = track_keys[index].iter().peekable();
-            let mut next = keys.next();
-            events.iter_mut().for_each(|event| {
-                let mut cond = true;
-                if let Some(next) = next {
-                    cond = if let Some(peek) = keys.peek() {
-                        (next.tick..peek.tick).contains(&event.tick)
-                    } else {
-                        (next.tick..).contains(&event.tick)
-                    };
-                    if let ValidEvent::Note(ref mut note) = event.event {
-                        if cond {
-                            *note += next.real;
-                        }
-                    }
-                }
-                if !cond {
-                    next = keys.next();
-                }
-
-                if indices.contains(&index) || event.event.is_tempo() {
-                    current.push(*event);
-                }
-            });
+            let mix = mixer.get(index).copied().unwrap_or_default();
+            if !indices.contains(&index) || !mix.audible(solo) {
+                continue;
+            }
+            Self::transpose(events, &track_keys[index]);
+            current.extend(events.iter().filter_map(|event| event.mixed(&mix)));
         }
+        drop(mixer);
         current.par_sort_by_key(|e| e.tick);
 
-        let mut tick = 0;
-        let mut tempo = DEFAULT_TEMPO_MPQ;
-        let mut time = 0;
-        let mut count = Vec::with_capacity(current.len());
-        *self.events.write() = current
+        let tempo = self.tempo.read();
+        let percussion = self.percussion.load();
+        let mut last = 0.0;
+        let events = current
             .into_iter()
             .filter_map(|event| match event.event {
-                ValidEvent::Note(press) => {
-                    let delay = Self::tick2micros(event.tick - tick, tempo, self.fps.load());
-                    time += delay as usize;
-                    count.push(time);
-                    tick = event.tick;
-                    Some(Event { press, delay })
-                }
-                ValidEvent::Tempo(t) => {
-                    tempo = t;
-                    None
+                ValidEvent::Note {
+                    key: press,
+                    len,
+                    vel,
+                    channel,
+                    ..
+                } => {
+                    let drum = channel == gm::PERCUSSION_CHANNEL;
+                    if drum && percussion == Percussion::Skip {
+                        return None;
+                    }
+                    let time = tempo.micros_at(event.tick);
+                    // Rounding errors of the delays must not add up over the song
+                    let delay = (time - last) as f32;
+                    last += delay as f64;
+                    Some(Event {
+                        press,
+                        delay,
+                        duration: (tempo.micros_at(event.tick + len) - time) as f32,
+                        track: event.track,
+                        chord: 1,
+                        bar: tempo.bar_at(event.tick),
+                        percussion: drum && percussion == Percussion::Drums,
+                        velocity: vel,
+                    })
                 }
-                _ => None,
+                ValidEvent::Other => None,
             })
             .collect();
-        *COUNT.write() = count;
+        drop(tempo);
+        let events = self.ghost.load().apply(events);
+        let events = self.melody.load().apply(events);
+        let mut events = self
+            .chord
+            .load()
+            .apply(events, self.profile.read().max_chord);
+        chord::group(&mut events);
+        let mut time = 0.0;
+        *self.count.write() = events
+            .iter()
+            .map(|e| {
+                time += e.delay as f64;
+                time.round() as usize
+            })
+            .collect();
+        *self.events.write() = events;
         self.hit_rate.store(self.detect(offset));
     }
 
-    pub fn playback(&self, offset: i32, mode: Mode) {
-        let send = get_map(mode);
-        PLAYING.store(true);
-        self.play(offset, send);
-        PLAYING.store(false);
-        LOCAL.store(0);
-    }
-
-    pub fn playback_one(self, offset: i32, mode: Mode, once: bool) {
-        POOL.spawn(move || {
-            loop {
-                self.playback(offset, mode);
-                if STATE.load() == State::Stop || once {
-                    break;
+    /// Notes of a track with the key signatures and its mix applied, muted or not,
+    /// drums only when they are played as melodic notes
+    pub fn track_notes(&self, index: usize) -> Vec<i32> {
+        let mut events = self.tracks.read()[index].clone();
+        Self::transpose(&mut events, &self.track_keys.read()[index]);
+        let mix = self.mixer.read().get(index).copied().unwrap_or_default();
+        let melodic = self.percussion.load() == Percussion::Melodic;
+        events
+            .into_iter()
+            .filter_map(|e| match e.mixed(&mix)?.event {
+                ValidEvent::Note { key, channel, .. }
+                    if melodic || channel != gm::PERCUSSION_CHANNEL =>
+                {
+                    Some(key)
                 }
-            }
-            STATE.store(State::Stop);
-        });
+                _ => None,
+            })
+            .collect()
     }
 
-    pub fn playback_list(
-        self,
-        mut index: usize,
-        dir_path: impl AsRef<Path>,
-        mode: Mode,
-        random: bool,
-    ) {
-        let path = dir_path.as_ref().to_path_buf();
-        let max = self.midis.read().len();
-        POOL.spawn(move || loop {
-            if index < max {
-                let midis = self.midis.read();
-                let file = midis[index].as_str();
-                CURRENT_MIDI.store(index);
-                self.read_midi(path.join(file));
-                self.playback(0, mode);
-                if let State::Stop = STATE.load() {
-                    break;
-                }
-                if random {
-                    let mut rng = rand::rng();
-                    index = rng.random_range(0..max);
-                } else {
-                    index += 1;
+    /// Shifts the notes by the `real` of the key signature they fall in, drums keep their note
+    fn transpose(events: &mut [RawEvent], keys: &[TrackKey]) {
+        let mut keys = keys.iter().peekable();
+        let mut next = keys.next();
+        events.iter_mut().for_each(|event| {
+            while keys.peek().is_some_and(|peek| peek.tick <= event.tick) {
+                next = keys.next();
+            }
+            if let Some(next) = next.filter(|next| next.tick <= event.tick) {
+                if let ValidEvent::Note {
+                    key: note, channel, ..
+                } = &mut event.event
+                {
+                    if *channel != gm::PERCUSSION_CHANNEL {
+                        *note += next.real;
+                    }
                 }
-            } else {
-                index = 0;
             }
         });
     }
 
-    pub fn playback_by(self, path: impl AsRef<Path>, offset: i32, play_mode: PlayMode, mode: Mode) {
-        match play_mode {
-            PlayMode::Once | PlayMode::OneLoop => {
-                STATE.store(State::Playing);
-                self.playback_one(offset, mode, matches!(play_mode, PlayMode::Once));
-            }
-            PlayMode::Loop | PlayMode::Random => {
-                if !self.midis.read().is_empty() {
-                    STATE.store(State::Playing);
-                    self.playback_list(
-                        CURRENT_MIDI.load(),
-                        path,
-                        mode,
-                        play_mode.eq(&PlayMode::Random),
-                    );
-                }
-            }
+    pub fn detect(&self, offset: i32) -> f32 {
+        let profile = self.profile.read();
+        let drums = self.drums.read();
+        let events = self.events.read();
+        let fold = self.fold.load();
+        let hits = events
+            .iter()
+            .filter(|e| e.key(offset, &profile, &drums, fold).is_some())
+            .count();
+        if events.is_empty() {
+            return 0.0;
         }
+        hits as f32 / events.len() as f32
     }
 
-    pub fn detect(&self, offset: i32) -> f32 {
-        const MAP: &[i32] = &[
-            24, 26, 28, 29, 31, 33, 35, 36, 38, 40, 41, 43, 45, 47, 48, 50, 52, 53, 55, 57, 59, 60,
-            62, 64, 65, 67, 69, 71, 72, 74, 76, 77, 79, 81, 83, 84, 86, 88, 89, 91, 93, 95,
-        ];
+    /// Whether every note of the track is on the percussion channel
+    pub fn is_percussion(&self, index: usize) -> bool {
+        let tracks = self.tracks.read();
+        let mut notes = tracks
+            .get(index)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| match e.event {
+                ValidEvent::Note { channel, .. } => Some(channel),
+                ValidEvent::Other => None,
+            })
+            .peekable();
+        notes.peek().is_some() && notes.all(|channel| channel == gm::PERCUSSION_CHANNEL)
+    }
 
+    /// The hit rate of every offset in `range`, best first.
+    /// Offsets with the same hit rate are ordered by the size of the shift.
+    pub fn auto_tune(&self, range: RangeInclusive<i32>) -> Vec<(i32, f32)> {
+        let profile = self.profile.read();
         let events = self.events.read();
-        let all = events.len() as f32;
-        let mut count = 0;
-        events.iter().for_each(|e| {
-            if MAP.contains(&(e.press + offset)) {
-                count += 1;
-            }
+        let fold = self.fold.load();
+        Self::rank(range, |offset| {
+            Self::rate(
+                events
+                    .iter()
+                    .filter(|e| !e.percussion)
+                    .map(|e| e.press + offset),
+                &profile,
+                fold,
+            )
+        })
+    }
+
+    /// Searches the best offset of each enabled track on top of the global `offset`,
+    /// and writes it into the `real` of every key of that track.
+    /// Returns the ranked candidates of each track.
+    pub fn auto_tune_tracks(
+        &self,
+        range: RangeInclusive<i32>,
+        offset: i32,
+    ) -> Vec<(usize, Vec<(i32, f32)>)> {
+        let profile = self.profile.read();
+        let fold = self.fold.load();
+        let tracks = self.tracks.read();
+        let mut track_keys = self.track_keys.write();
+        let mixer = self.mixer.read();
+        self.current_range()
+            .into_iter()
+            .filter_map(|index| {
+                let mix = mixer.get(index).copied().unwrap_or_default();
+                // Drums keep their note whatever the track is tuned to
+                let notes = tracks[index]
+                    .iter()
+                    .filter_map(|e| match e.mixed(&mix)?.event {
+                        ValidEvent::Note { key, channel, .. }
+                            if channel != gm::PERCUSSION_CHANNEL =>
+                        {
+                            Some(key)
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if notes.is_empty() {
+                    return None;
+                }
+                let ranked = Self::rank(range.clone(), |real| {
+                    Self::rate(
+                        notes.iter().map(|note| note + real + offset),
+                        &profile,
+                        fold,
+                    )
+                });
+                let &(best, _) = ranked.first()?;
+                let keys = &mut track_keys[index];
+                if keys.first().is_none_or(|key| key.tick > 0) {
+                    keys.insert(
+                        0,
+                        TrackKey {
+                            tick: 0,
+                            key: 0,
+                            backup: 0,
+                            real: 0,
+                        },
+                    );
+                }
+                keys.iter_mut().for_each(|key| {
+                    key.key = key.backup + best;
+                    key.real = best;
+                });
+                Some((index, ranked))
+            })
+            .collect()
+    }
+
+    fn rank(range: RangeInclusive<i32>, rate: impl Fn(i32) -> f32) -> Vec<(i32, f32)> {
+        let mut ranked = range
+            .map(|offset| (offset, rate(offset)))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(a, rate_a), (b, rate_b)| {
+            rate_b
+                .total_cmp(rate_a)
+                .then(a.abs().cmp(&b.abs()))
+                .then(a.cmp(b))
         });
-        count as f32 / all
+        ranked
     }
 
-    /// 1. The difference in microseconds between two events
-    /// 2. The time in microseconds this event was in track
-    #[inline]
-    fn tick2micros(tick: u32, tempo_mpq: u32, fps: f32) -> f32 {
-        tick as f32 * tempo_mpq as f32 / fps
+    fn rate(notes: impl Iterator<Item = i32>, profile: &InstrumentProfile, fold: Fold) -> f32 {
+        let (all, count) = notes.fold((0, 0), |(all, count), note| {
+            (
+                all + 1,
+                count + fold.apply(note, profile).is_some() as usize,
+            )
+        });
+        if all == 0 {
+            return 0.0;
+        }
+        count as f32 / all as f32
     }
 
-    /// 1. MPQ-Tempo to BPM-Tempo
-    /// 2. BPM-Tempo to MPQ-Tempo
-    #[allow(dead_code)]
-    #[inline]
-    fn convert_tempo(mut tempo: f32) -> f32 {
-        if tempo <= 0.0 {
-            tempo = 1.0;
+    /// Microseconds from the start of the song to `seek`,
+    /// `None` for an event that does not exist or a bar of a timecode file
+    pub fn time_of(&self, seek: Seek) -> Option<usize> {
+        match seek {
+            Seek::Event(position) => self.count.read().get(position).copied(),
+            Seek::Time(micros) => Some(micros),
+            Seek::Bar { bar, beat } => {
+                let tempo = self.tempo.read();
+                let tick = tempo.tick_at_bar(bar, beat)?;
+                Some(tempo.micros_at(tick).round() as usize)
+            }
         }
-        60000000.0 / tempo
     }
 
     pub fn current_range(&self) -> Vec<usize> {
@@ -370,56 +736,160 @@ impl Midi {
             )
             .collect()
     }
-
-    pub fn get_midis_path(&self, path: impl AsRef<Path>) {
-        let Ok(entry) = path.as_ref().read_dir() else {
-            return;
-        };
-        let midis = entry
-            .into_iter()
-            .filter_map(|entry| {
-                let Ok(entry) = entry else {
-                    return None;
-                };
-                if entry.path().extension().unwrap_or_default().eq("mid") {
-                    Some(entry.file_name().to_string_lossy().to_string())
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-        CURRENT_MIDI.store(0);
-        *self.midis.write() = midis;
-    }
-
-    pub fn switch_midi(&self, index: usize, path: impl AsRef<Path>) {
-        CURRENT_MIDI.store(index);
-        STATE.store(State::Stop);
-        self.read_midi(path);
-    }
 }
 
 #[derive(Debug, Copy, Clone)]
 enum ValidEvent {
-    Note(i32),
-    Tempo(u32),
+    /// The key, how many ticks it is held for, how hard it is played
+    /// and the channel and program it is played with
+    Note {
+        key: i32,
+        len: u32,
+        vel: u8,
+        channel: u8,
+        program: u8,
+    },
     Other,
 }
 
-impl ValidEvent {
-    fn is_tempo(&self) -> bool {
-        matches!(self, ValidEvent::Tempo(_))
-    }
-}
-
 #[derive(Debug, Copy, Clone)]
 pub struct RawEvent {
     event: ValidEvent,
     tick: u32,
+    track: usize,
+}
+
+impl RawEvent {
+    /// The event with the transposition of `mix`, `None` for a note below its velocity,
+    /// drums are not transposed
+    fn mixed(&self, mix: &TrackMix) -> Option<RawEvent> {
+        match self.event {
+            ValidEvent::Note { vel, .. } if vel < mix.min_velocity => None,
+            ValidEvent::Note { channel, .. } if channel == gm::PERCUSSION_CHANNEL => Some(*self),
+            ValidEvent::Note { key, .. } => {
+                let mut event = *self;
+                if let ValidEvent::Note { key: shifted, .. } = &mut event.event {
+                    *shifted = key + mix.transpose;
+                }
+                Some(event)
+            }
+            ValidEvent::Other => Some(*self),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct Event {
     pub press: i32,
     pub delay: f32,
+    /// How long the note sounds in microseconds, zero when it is never released
+    pub duration: f32,
+    /// Index of the track the note comes from
+    pub track: usize,
+    /// Number of events from this one on that are pressed together,
+    /// zero for the rest of a chord
+    pub chord: usize,
+    /// Where the note falls in the bars, `None` for timecode files
+    pub bar: Option<BarBeat>,
+    /// The note is a drum of the percussion channel, played with the drum profile
+    pub percussion: bool,
+    /// How hard the note is played, from 1 to 127
+    pub velocity: u8,
+}
+
+impl Event {
+    /// The key the note is played with, percussion is neither shifted by `offset` nor folded
+    pub fn key(
+        &self,
+        offset: i32,
+        profile: &InstrumentProfile,
+        drums: &InstrumentProfile,
+        fold: Fold,
+    ) -> Option<VKey> {
+        if self.percussion {
+            drums.key(self.press)
+        } else {
+            fold.apply(self.press + offset, profile)
+        }
+    }
+}
+
+/// The events of `events` that `keep` is `true` for,
+/// the delay of an event left out is added to the next one
+pub fn retain(events: Vec<Event>, keep: impl IntoIterator<Item = bool>) -> Vec<Event> {
+    let mut delay = 0.0;
+    events
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(mut event, keep)| {
+            delay += event.delay;
+            keep.then(|| {
+                event.delay = delay;
+                delay = 0.0;
+                event
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+impl Event {
+    /// A note of track 0 that is not part of a chord yet
+    pub(crate) fn note(press: i32, delay: f32) -> Self {
+        Self {
+            press,
+            delay,
+            duration: 0.0,
+            track: 0,
+            chord: 1,
+            bar: None,
+            percussion: false,
+            velocity: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_prefers_the_best_rate() {
+        let ranked = Midi::rank(-3..=3, |offset| if offset == 2 { 1.0 } else { 0.5 });
+        assert_eq!(ranked[0], (2, 1.0));
+        assert_eq!(ranked.len(), 7);
+    }
+
+    #[test]
+    fn rank_breaks_ties_toward_the_smallest_shift() {
+        let ranked = Midi::rank(-12..=12, |offset| if offset % 12 == 0 { 1.0 } else { 0.0 });
+        let offsets = ranked.iter().map(|(offset, _)| *offset).collect::<Vec<_>>();
+        assert_eq!(offsets[..3], [0, -12, 12]);
+        assert_eq!(offsets[3..5], [-1, 1]);
+    }
+
+    #[test]
+    fn rank_of_an_empty_range() {
+        let (low, high) = (5, -5);
+        assert!(Midi::rank(low..=high, |_| 1.0).is_empty());
+    }
+
+    #[test]
+    fn rate_without_notes() {
+        let profile = InstrumentProfile::default();
+        assert_eq!(Midi::rate([].into_iter(), &profile, Fold::Drop), 0.0);
+    }
+
+    #[test]
+    fn rate_counts_bound_notes() {
+        let profile = InstrumentProfile {
+            keys: [(60, VKey::A), (62, VKey::S)].into(),
+            range: None,
+            ..InstrumentProfile::default()
+        };
+        assert_eq!(
+            Midi::rate([60, 61, 62, 62].into_iter(), &profile, Fold::Drop),
+            0.75
+        );
+    }
 }
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use parking_lot::Mutex;
+
+use crate::util::VKey;
+
+/// What happened during playback, in the order it happened
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    /// A song was read, `length` is in microseconds
+    Loaded {
+        name: String,
+        events: usize,
+        length: usize,
+    },
+    /// A key was sent for the event at `index`, `time` is in nanoseconds since playback started
+    Note {
+        index: usize,
+        pitch: i32,
+        key: VKey,
+        time: u64,
+    },
+    Paused {
+        position: usize,
+    },
+    Resumed {
+        position: usize,
+    },
+    Seeked {
+        position: usize,
+    },
+    /// The song ended, `stopped` is set when it was stopped before its end
+    Finished {
+        stopped: bool,
+    },
+    /// A looped passage started over, `speed` is relative to the first pass
+    Looped {
+        repeat: u32,
+        speed: f32,
+    },
+    /// The playlist moved on to the file at `index`
+    Advanced {
+        index: usize,
+        file: String,
+    },
+    Error(String),
+}
+
+/// Every subscriber of a player, the ones that dropped their receiver are forgotten
+#[derive(Debug, Clone, Default)]
+pub struct Observers(Arc<Mutex<Vec<Sender<PlayerEvent>>>>);
+
+impl Observers {
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (sender, receiver) = unbounded();
+        self.0.lock().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: PlayerEvent) {
+        self.0
+            .lock()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumIter};
+
+use crate::util::VKey;
+
+pub use record::Recorder;
+
+mod record;
+#[cfg(windows)]
+mod win32;
+#[cfg(target_os = "linux")]
+mod xtest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    Press(VKey),
+    Release(VKey),
+}
+
+/// Anything that can "type" the keys produced by playback.
+pub trait KeyOutput: Debug + Send + Sync {
+    fn press(&self, vk: VKey);
+
+    fn release(&self, vk: VKey);
+
+    fn tap(&self, vk: VKey) {
+        self.press(vk);
+        self.release(vk);
+    }
+
+    /// Sends the actions of a whole chord at once, in order
+    fn batch(&self, actions: &[KeyAction]) {
+        actions.iter().for_each(|action| match *action {
+            KeyAction::Press(vk) => self.press(vk),
+            KeyAction::Release(vk) => self.release(vk),
+        });
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, EnumIter, AsRefStr, Serialize, Deserialize,
+)]
+pub enum Backend {
+    /// SendInput on Windows, XTest on Linux
+    #[default]
+    System,
+    /// Keep the keys in memory instead of sending them
+    Record,
+}
+
+impl Backend {
+    pub fn open(self) -> Arc<dyn KeyOutput> {
+        match self {
+            Backend::System => system(),
+            Backend::Record => Arc::new(Recorder::default()),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn system() -> Arc<dyn KeyOutput> {
+    Arc::new(win32::SendInput)
+}
+
+#[cfg(target_os = "linux")]
+fn system() -> Arc<dyn KeyOutput> {
+    match xtest::XTest::open() {
+        Some(xtest) => Arc::new(xtest),
+        None => {
+            eprintln!("XTest is unavailable, keys will only be recorded");
+            Arc::new(Recorder::default())
+        }
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn system() -> Arc<dyn KeyOutput> {
+    Arc::new(Recorder::default())
+}
+
+#[inline]
+pub fn is_pressed(vk: VKey) -> bool {
+    #[cfg(windows)]
+    return win32::is_pressed(vk);
+    #[cfg(target_os = "linux")]
+    return xtest::is_pressed(vk);
+    #[cfg(not(any(windows, target_os = "linux")))]
+    return {
+        let _ = vk;
+        false
+    };
+}
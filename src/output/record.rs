@@ -0,0 +1,46 @@
+use parking_lot::Mutex;
+
+use crate::output::{KeyAction, KeyOutput};
+use crate::util::VKey;
+
+/// In-memory backend, every key is stored in the order it was sent
+#[derive(Debug, Default)]
+pub struct Recorder {
+    actions: Mutex<Vec<KeyAction>>,
+}
+
+impl Recorder {
+    pub fn actions(&self) -> Vec<KeyAction> {
+        self.actions.lock().clone()
+    }
+
+    pub fn take(&self) -> Vec<KeyAction> {
+        std::mem::take(&mut *self.actions.lock())
+    }
+
+    /// Keys that were tapped, i.e. the presses only
+    pub fn pressed(&self) -> Vec<VKey> {
+        self.actions
+            .lock()
+            .iter()
+            .filter_map(|action| match action {
+                KeyAction::Press(vk) => Some(*vk),
+                KeyAction::Release(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl KeyOutput for Recorder {
+    fn press(&self, vk: VKey) {
+        self.actions.lock().push(KeyAction::Press(vk));
+    }
+
+    fn release(&self, vk: VKey) {
+        self.actions.lock().push(KeyAction::Release(vk));
+    }
+
+    fn batch(&self, actions: &[KeyAction]) {
+        self.actions.lock().extend_from_slice(actions);
+    }
+}
@@ -0,0 +1,74 @@
+use std::mem;
+
+use winapi::um::winuser::{
+    self, GetAsyncKeyState, INPUT_u, MapVirtualKeyA, INPUT, INPUT_KEYBOARD, KEYBDINPUT,
+    KEYEVENTF_KEYUP, MAPVK_VK_TO_VSC,
+};
+
+use crate::output::{KeyAction, KeyOutput};
+use crate::util::VKey;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendInput;
+
+impl SendInput {
+    fn send(inputs: &mut [INPUT]) {
+        unsafe {
+            winuser::SendInput(
+                inputs.len() as _,
+                inputs.as_mut_ptr(),
+                mem::size_of::<INPUT>() as _,
+            );
+        }
+    }
+}
+
+impl KeyOutput for SendInput {
+    fn press(&self, vk: VKey) {
+        Self::send(&mut [input(vk, 0)]);
+    }
+
+    fn release(&self, vk: VKey) {
+        Self::send(&mut [input(vk, KEYEVENTF_KEYUP)]);
+    }
+
+    fn tap(&self, vk: VKey) {
+        Self::send(&mut [input(vk, 0), input(vk, KEYEVENTF_KEYUP)]);
+    }
+
+    fn batch(&self, actions: &[KeyAction]) {
+        let mut inputs = actions
+            .iter()
+            .map(|action| match *action {
+                KeyAction::Press(vk) => input(vk, 0),
+                KeyAction::Release(vk) => input(vk, KEYEVENTF_KEYUP),
+            })
+            .collect::<Vec<_>>();
+        Self::send(&mut inputs);
+    }
+}
+
+#[inline]
+fn input(vk: VKey, flags: u32) -> INPUT {
+    unsafe {
+        INPUT {
+            type_: INPUT_KEYBOARD,
+            u: {
+                let mut u = mem::zeroed::<INPUT_u>();
+                *u.ki_mut() = KEYBDINPUT {
+                    wVk: vk as _,
+                    wScan: MapVirtualKeyA(vk as _, MAPVK_VK_TO_VSC) as _,
+                    dwFlags: flags,
+                    time: 0,
+                    dwExtraInfo: 0,
+                };
+                u
+            },
+        }
+    }
+}
+
+#[inline]
+pub fn is_pressed(vk: VKey) -> bool {
+    unsafe { GetAsyncKeyState(vk as _) >> 15 != 0 }
+}
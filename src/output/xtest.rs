@@ -0,0 +1,204 @@
+use std::fmt::{self, Debug, Formatter};
+use std::os::raw::{c_char, c_uint, c_ulong};
+use std::ptr;
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use x11_dl::keysym::*;
+use x11_dl::xlib::{Display, Xlib};
+use x11_dl::xtest::Xf86vmode as Xtst;
+
+use crate::output::{KeyAction, KeyOutput};
+use crate::util::VKey;
+
+/// Fake key events through the XTest extension
+pub struct XTest {
+    xlib: Xlib,
+    xtst: Xtst,
+    display: Mutex<Conn>,
+}
+
+struct Conn(*mut Display);
+
+// The display is only ever touched while the mutex is held
+unsafe impl Send for Conn {}
+
+impl Debug for XTest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XTest")
+            .field("display", &self.display.lock().0)
+            .finish()
+    }
+}
+
+impl Drop for XTest {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xlib.XCloseDisplay)(self.display.get_mut().0);
+        }
+    }
+}
+
+impl XTest {
+    pub fn open() -> Option<Self> {
+        let xlib = Xlib::open().ok()?;
+        let xtst = Xtst::open().ok()?;
+        let display = unsafe { (xlib.XOpenDisplay)(ptr::null()) };
+        if display.is_null() {
+            return None;
+        }
+        Some(Self {
+            xlib,
+            xtst,
+            display: Mutex::new(Conn(display)),
+        })
+    }
+
+    /// Queues every action and flushes them together
+    fn send(&self, actions: &[KeyAction]) {
+        let display = self.display.lock();
+        unsafe {
+            for action in actions {
+                let (vk, down) = match *action {
+                    KeyAction::Press(vk) => (vk, true),
+                    KeyAction::Release(vk) => (vk, false),
+                };
+                let code = (self.xlib.XKeysymToKeycode)(display.0, keysym(vk) as c_ulong);
+                if code != 0 {
+                    (self.xtst.XTestFakeKeyEvent)(display.0, code as c_uint, down as _, 0);
+                }
+            }
+            (self.xlib.XFlush)(display.0);
+        }
+    }
+
+    fn is_pressed(&self, vk: VKey) -> bool {
+        let display = self.display.lock();
+        let mut keys: [c_char; 32] = [0; 32];
+        unsafe {
+            let code = (self.xlib.XKeysymToKeycode)(display.0, keysym(vk) as c_ulong) as usize;
+            if code == 0 {
+                return false;
+            }
+            (self.xlib.XQueryKeymap)(display.0, keys.as_mut_ptr());
+            keys[code / 8] as u8 & (1 << (code % 8)) != 0
+        }
+    }
+}
+
+impl KeyOutput for XTest {
+    fn press(&self, vk: VKey) {
+        self.send(&[KeyAction::Press(vk)]);
+    }
+
+    fn release(&self, vk: VKey) {
+        self.send(&[KeyAction::Release(vk)]);
+    }
+
+    fn tap(&self, vk: VKey) {
+        self.send(&[KeyAction::Press(vk), KeyAction::Release(vk)]);
+    }
+
+    fn batch(&self, actions: &[KeyAction]) {
+        self.send(actions);
+    }
+}
+
+#[inline]
+pub fn is_pressed(vk: VKey) -> bool {
+    static STATE: LazyLock<Option<XTest>> = LazyLock::new(XTest::open);
+    STATE.as_ref().is_some_and(|x| x.is_pressed(vk))
+}
+
+fn keysym(vk: VKey) -> c_uint {
+    match vk {
+        VKey::A
+        | VKey::B
+        | VKey::C
+        | VKey::D
+        | VKey::E
+        | VKey::F
+        | VKey::G
+        | VKey::H
+        | VKey::I
+        | VKey::J
+        | VKey::K
+        | VKey::L
+        | VKey::M
+        | VKey::N
+        | VKey::O
+        | VKey::P
+        | VKey::Q
+        | VKey::R
+        | VKey::S
+        | VKey::T
+        | VKey::U
+        | VKey::V
+        | VKey::W
+        | VKey::X
+        | VKey::Y
+        | VKey::Z => XK_a + (vk as c_uint - VKey::A as c_uint),
+        VKey::Num0
+        | VKey::Num1
+        | VKey::Num2
+        | VKey::Num3
+        | VKey::Num4
+        | VKey::Num5
+        | VKey::Num6
+        | VKey::Num7
+        | VKey::Num8
+        | VKey::Num9 => XK_0 + (vk as c_uint - VKey::Num0 as c_uint),
+        VKey::Np0
+        | VKey::Np1
+        | VKey::Np2
+        | VKey::Np3
+        | VKey::Np4
+        | VKey::Np5
+        | VKey::Np6
+        | VKey::Np7
+        | VKey::Np8
+        | VKey::Np9 => XK_KP_0 + (vk as c_uint - VKey::Np0 as c_uint),
+        VKey::F1
+        | VKey::F2
+        | VKey::F3
+        | VKey::F4
+        | VKey::F5
+        | VKey::F6
+        | VKey::F7
+        | VKey::F8
+        | VKey::F9
+        | VKey::F10
+        | VKey::F11
+        | VKey::F12 => XK_F1 + (vk as c_uint - VKey::F1 as c_uint),
+        VKey::Semicolon => XK_semicolon,
+        VKey::Equal => XK_equal,
+        VKey::Comma => XK_comma,
+        VKey::Minus => XK_minus,
+        VKey::Period => XK_period,
+        VKey::Slash => XK_slash,
+        VKey::Backquote => XK_grave,
+        VKey::BracketLeft => XK_bracketleft,
+        VKey::Backslash => XK_backslash,
+        VKey::BracketRight => XK_bracketright,
+        VKey::Quote => XK_apostrophe,
+        VKey::BackSpace => XK_BackSpace,
+        VKey::Tab => XK_Tab,
+        VKey::Enter => XK_Return,
+        VKey::Shift => XK_Shift_L,
+        VKey::Control => XK_Control_L,
+        VKey::Alt => XK_Alt_L,
+        VKey::CapsLock => XK_Caps_Lock,
+        VKey::Esc => XK_Escape,
+        VKey::Space => XK_space,
+        VKey::Left => XK_Left,
+        VKey::Up => XK_Up,
+        VKey::Right => XK_Right,
+        VKey::Down => XK_Down,
+        VKey::NpMultiply => XK_KP_Multiply,
+        VKey::NpAdd => XK_KP_Add,
+        VKey::NpEnter => XK_KP_Enter,
+        VKey::NpSubtract => XK_KP_Subtract,
+        VKey::NpDecimal => XK_KP_Decimal,
+        VKey::NpDivide => XK_KP_Divide,
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam::channel::Receiver;
+use parking_lot::{Mutex, RwLock};
+use rand::Rng;
+use rfd::MessageButtons;
+
+use crate::control::{Controller, Seek};
+use crate::maps::Fold;
+use crate::midi::{Event, LoadError, Midi, State, Sustain, TrackMix};
+use crate::observe::{Observers, PlayerEvent};
+use crate::output::{KeyAction, KeyOutput};
+use crate::profile::InstrumentProfile;
+use crate::scheduler::{Lateness, Scheduler};
+use crate::ui::play::PlayMode;
+use crate::util::VKey;
+use crate::POOL;
+
+pub const DEFAULT_SPIN: u32 = 1000;
+
+/// How the keys are played, read when a song starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub sustain: Sustain,
+    /// Microseconds before each note that are spun instead of slept
+    pub spin: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            sustain: Sustain::default(),
+            spin: DEFAULT_SPIN,
+        }
+    }
+}
+
+/// A passage played again and again, for practicing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopRegion {
+    /// Microseconds from the start of the song to A
+    pub start: usize,
+    /// Microseconds from the start of the song to B, the chords from B on are not looped
+    pub end: usize,
+    /// How many times the passage is played before the song goes on, forever when `None`
+    pub times: Option<u32>,
+    /// Beats waited before each pass
+    pub count_in: u32,
+    /// Part of the speed taken off on every repetition, 0.1 plays each pass 10% slower
+    pub slow_down: f32,
+}
+
+impl LoopRegion {
+    /// The passage between `a` and `b` in either order, `None` when they are the same
+    pub fn new(a: usize, b: usize) -> Option<Self> {
+        (a != b).then(|| Self {
+            start: a.min(b),
+            end: a.max(b),
+            times: None,
+            count_in: 0,
+            slow_down: 0.0,
+        })
+    }
+
+    /// Whether a chord of the song with chords at `count` starts between A and B,
+    /// a passage without one is not looped
+    pub fn has_notes(&self, count: &[usize]) -> bool {
+        count
+            .iter()
+            .any(|at| at + 1 >= self.start && *at < self.end)
+    }
+
+    /// The speed of the pass after `repeat` repetitions relative to the first pass
+    pub fn rate(&self, repeat: u32) -> f32 {
+        (1.0 - self.slow_down)
+            .clamp(0.0, 1.0)
+            .powi(repeat.min(i32::MAX as u32) as i32)
+            .max(0.1)
+    }
+}
+
+/// Nanoseconds `micros` of the song take at `speed`
+#[inline]
+fn scaled(micros: f32, speed: f32) -> u64 {
+    (micros as f64 * 1000.0 / speed as f64) as u64
+}
+
+/// Plays a song or a playlist on its own thread, every player has its own state
+#[derive(Debug, Clone, Default)]
+pub struct Player {
+    pub midi: Midi,
+    pub control: Arc<Controller>,
+    pub settings: Arc<AtomicCell<Settings>>,
+    /// Files of the playlist
+    pub midis: Arc<RwLock<Vec<String>>>,
+    /// Index of the playlist file being played
+    pub current: Arc<AtomicCell<usize>>,
+    /// Index and note of the events dropped by `Fold::DropLog` during the last playback
+    pub dropped: Arc<RwLock<Vec<(usize, i32)>>>,
+    /// How late each chord of the last playback was sent
+    pub lateness: Arc<RwLock<Lateness>>,
+    /// The passage to repeat instead of playing the whole song
+    pub region: Arc<AtomicCell<Option<LoopRegion>>>,
+    /// The track mix saved for each song, by file name
+    pub mixes: Arc<RwLock<BTreeMap<String, Vec<TrackMix>>>>,
+    observers: Observers,
+    worker: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Player {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A player of a song that is already loaded
+    pub fn with_midi(midi: Midi) -> Self {
+        Self {
+            midi,
+            ..Self::default()
+        }
+    }
+
+    /// Every event published from now on is sent to the receiver
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.observers.subscribe()
+    }
+
+    /// Reads a song into the player, errors are published as well
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        if let Err(e) = self.midi.read_midi(path) {
+            self.observers.publish(PlayerEvent::Error(format!(
+                "{}: {e}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            )));
+            return Err(e);
+        }
+        let name = self.midi.name.read().clone().unwrap_or_default();
+        if let Some(mix) = self.mixes.read().get(&name) {
+            let mut mixer = self.midi.mixer.write();
+            mixer.iter_mut().zip(mix).for_each(|(to, from)| *to = *from);
+            drop(mixer);
+            self.midi.merge_tracks(&self.midi.current_range(), 0);
+        }
+        self.observers.publish(PlayerEvent::Loaded {
+            name,
+            events: self.midi.events.read().len(),
+            length: self.midi.count.read().last().copied().unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    /// Lets the user pick a file to load
+    pub fn init(self) {
+        POOL.spawn(move || {
+            if let Some(ref path) = rfd::FileDialog::new()
+                .add_filter("MIDI File", &["mid"])
+                .pick_file()
+            {
+                if let Err(e) = self.load(path) {
+                    rfd::MessageDialog::new()
+                        .set_description(format!("无法打开MIDI文件\n{e}"))
+                        .set_buttons(MessageButtons::Ok)
+                        .show();
+                }
+            }
+        });
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.control.state() != State::Stop
+    }
+
+    /// Whether the playback thread is still running
+    pub fn is_running(&self) -> bool {
+        self.worker
+            .lock()
+            .as_ref()
+            .is_some_and(|worker| !worker.is_finished())
+    }
+
+    fn play(&self, offset: i32, profile: &InstrumentProfile, output: &dyn KeyOutput) {
+        let events = self.midi.events.read();
+        let count = self.midi.count.read();
+        let drums = self.midi.drums.read().clone();
+        let settings = self.settings.load();
+        let (sustain, fold) = (settings.sustain, self.midi.fold.load());
+        self.dropped.write().clear();
+        self.lateness.write().clear();
+        // Held keys ordered by the time they are released
+        let mut held: Vec<(u64, VKey)> = vec![];
+        // When each key was last pressed
+        let mut pressed: HashMap<VKey, u64> = HashMap::new();
+        // Downs of the current chord followed by the ups of its tapped keys
+        let mut actions = vec![];
+        let mut ups = vec![];
+        // Notes of the current chord, published once its keys are sent
+        let mut notes = vec![];
+        let mut scheduler = Scheduler::new(Duration::from_micros(settings.spin as u64));
+        let mut speed = self.control.speed();
+        // Deadline of the previous chord in nanoseconds
+        let mut input_time = 0;
+        // Deadline of the next chord once it is known
+        let mut next = None;
+        // Microseconds from the start of the song to the previous chord
+        let mut song_time = 0;
+        let mut i = 0;
+        // The passage being looped, how many times it was repeated and how fast it is played
+        let mut looping = None;
+        let mut region = None;
+        let mut repeat = 0;
+        let mut rate = 1.0;
+        'play: loop {
+            if self.region.load() != looping {
+                looping = self.region.load();
+                region = looping.filter(|passage| passage.has_notes(&count));
+                repeat = 0;
+                rate = 1.0;
+                if let Some(passage) = region.filter(|_| input_time == 0) {
+                    // Playback starts at A
+                    let (position, wait) = self.locate(Seek::Time(passage.start), &events, &count);
+                    i = position;
+                    song_time = passage.start;
+                    next = Some(scaled(self.count_in(&passage) + wait as f32, speed));
+                }
+            }
+            if let Some(passage) =
+                region.filter(|passage| i >= events.len() || count[i] >= passage.end)
+            {
+                // B comes after the rest of the gap that follows the last chord
+                let end = passage.end.min(count.last().copied().unwrap_or_default());
+                let end_time =
+                    input_time + scaled(end.saturating_sub(song_time) as f32, speed * rate);
+                repeat += 1;
+                if passage.times.is_some_and(|times| repeat >= times) {
+                    region = None;
+                    rate = 1.0;
+                    if let Some(at) = count.get(i) {
+                        next = Some(end_time + scaled(at.saturating_sub(end) as f32, speed));
+                    }
+                } else {
+                    held.drain(..).for_each(|(_, vk)| output.release(vk));
+                    pressed.clear();
+                    rate = passage.rate(repeat);
+                    let (position, wait) = self.locate(Seek::Time(passage.start), &events, &count);
+                    i = position;
+                    song_time = passage.start;
+                    next = Some(
+                        end_time + scaled(self.count_in(&passage) + wait as f32, speed * rate),
+                    );
+                    self.observers.publish(PlayerEvent::Looped {
+                        repeat,
+                        speed: rate,
+                    });
+                }
+            }
+            if i >= events.len() {
+                break;
+            }
+            self.control.set_position(i);
+            let e = events[i];
+            let chord = &events[i..(i + e.chord.max(1)).min(events.len())];
+            let deadline = *next.get_or_insert(input_time + scaled(e.delay, speed * rate));
+            let ready = loop {
+                match held.first() {
+                    Some(&(release_time, vk)) if release_time <= deadline => {
+                        if scheduler.wait_until(release_time, &self.control).is_none() {
+                            break None;
+                        }
+                        output.release(vk);
+                        held.remove(0);
+                    }
+                    _ => break scheduler.wait_until(deadline, &self.control),
+                }
+            };
+            let Some(late) = ready else {
+                // A command came in before the chord was due
+                let mut paused = false;
+                loop {
+                    let status = self.control.take();
+                    if status.speed != speed {
+                        let now = scheduler.elapsed();
+                        let rescale = |time: u64| {
+                            now + (time.saturating_sub(now) as f64 * speed as f64
+                                / status.speed as f64) as u64
+                        };
+                        next = Some(rescale(deadline));
+                        held.iter_mut().for_each(|(time, _)| *time = rescale(*time));
+                        speed = status.speed;
+                    }
+                    if let Some(seek) = status.seek {
+                        held.drain(..).for_each(|(_, vk)| output.release(vk));
+                        pressed.clear();
+                        let (position, wait) = self.locate(seek, &events, &count);
+                        if position >= events.len() {
+                            break 'play;
+                        }
+                        i = position;
+                        // The chord is due once the rest of the gap before it has passed
+                        song_time = count[i].saturating_sub(wait);
+                        next = Some(scheduler.elapsed() + scaled(wait as f32, speed * rate));
+                        self.observers.publish(PlayerEvent::Seeked { position: i });
+                    }
+                    match status.state {
+                        State::Playing if paused => {
+                            self.observers.publish(PlayerEvent::Resumed { position: i });
+                            break;
+                        }
+                        State::Playing => break,
+                        State::Pause => {
+                            held.drain(..).for_each(|(_, vk)| output.release(vk));
+                            if !paused {
+                                self.observers.publish(PlayerEvent::Paused { position: i });
+                                paused = true;
+                            }
+                            let paused_at = scheduler.elapsed();
+                            self.control.wait();
+                            scheduler.resume(paused_at);
+                        }
+                        State::Stop => break 'play,
+                    }
+                }
+                continue;
+            };
+            next = None;
+            input_time = deadline;
+            song_time = count[i];
+
+            for (index, e) in (i..).zip(chord) {
+                let (pitch, key) = if e.percussion {
+                    (e.press, drums.key(e.press))
+                } else {
+                    (e.press + offset, fold.apply(e.press + offset, profile))
+                };
+                let Some(vk) = key else {
+                    if fold == Fold::DropLog {
+                        self.dropped.write().push((index, pitch));
+                    }
+                    continue;
+                };
+                // Chords are already cut to the limit when the tracks are merged
+                if actions.contains(&KeyAction::Press(vk))
+                    || pressed
+                        .get(&vk)
+                        .is_some_and(|last| input_time.saturating_sub(*last) < profile.interval(vk))
+                {
+                    continue;
+                }
+                pressed.insert(vk, input_time);
+                notes.push(PlayerEvent::Note {
+                    index,
+                    pitch,
+                    key: vk,
+                    time: input_time,
+                });
+                match sustain.hold(e.duration) {
+                    Some(hold) => {
+                        if let Some(index) = held.iter().position(|(_, key)| *key == vk) {
+                            held.remove(index);
+                            actions.push(KeyAction::Release(vk));
+                        }
+                        actions.push(KeyAction::Press(vk));
+                        let release_time = input_time + scaled(hold, speed * rate);
+                        let index = held.partition_point(|(time, _)| *time <= release_time);
+                        held.insert(index, (release_time, vk));
+                    }
+                    None => {
+                        actions.push(KeyAction::Press(vk));
+                        ups.push(KeyAction::Release(vk));
+                    }
+                }
+            }
+            actions.append(&mut ups);
+            if !actions.is_empty() {
+                output.batch(&actions);
+                actions.clear();
+                self.lateness.write().record(late);
+            }
+            notes
+                .drain(..)
+                .for_each(|note| self.observers.publish(note));
+            i += chord.len();
+        }
+        for (release_time, vk) in held {
+            if self.control.state() != State::Stop {
+                scheduler.wait_until(release_time, &self.control);
+            }
+            output.release(vk);
+        }
+    }
+
+    /// Microseconds of the count-in before each pass of `passage`
+    fn count_in(&self, passage: &LoopRegion) -> f32 {
+        let tempo = self.midi.tempo.read();
+        let beat = tempo.beat_micros(tempo.tick_at(passage.start as f64));
+        (beat * passage.count_in as f64) as f32
+    }
+
+    /// The first chord at or after `seek` and the microseconds between `seek` and that chord,
+    /// the index is past the last event when `seek` is after the end of the song
+    fn locate(&self, seek: Seek, events: &[Event], count: &[usize]) -> (usize, usize) {
+        let time = match seek {
+            Seek::Event(position) => {
+                let mut position = position.min(events.len().saturating_sub(1));
+                while position > 0 && events[position].chord == 0 {
+                    position -= 1;
+                }
+                return (position, 0);
+            }
+            seek => match self.midi.time_of(seek) {
+                Some(time) => time,
+                None => return (self.control.position(), 0),
+            },
+        };
+        // A note on the target must not be missed for a microsecond of rounding
+        let mut position = count.partition_point(|at| at + 1 < time);
+        while position > 0 && position < events.len() && events[position].chord == 0 {
+            position += 1;
+        }
+        let wait = count.get(position).map_or(0, |at| at.saturating_sub(time));
+        (position, wait)
+    }
+
+    /// Plays the merged events on the current thread until they end or are stopped
+    pub fn playback(&self, offset: i32, output: &dyn KeyOutput) {
+        let profile = self.midi.profile.read().clone();
+        self.play(offset, &profile, output);
+        self.control.set_position(0);
+        self.observers.publish(PlayerEvent::Finished {
+            stopped: self.control.state() == State::Stop,
+        });
+    }
+
+    /// Stops the previous playback thread and starts `f` on a new one
+    fn spawn(&self, f: impl FnOnce(Player) + Send + 'static) {
+        let mut worker = self.worker.lock();
+        if let Some(worker) = worker.take() {
+            self.control.stop();
+            worker.join().ok();
+        }
+        self.control.play();
+        let player = self.clone();
+        *worker = Some(thread::spawn(move || f(player)));
+    }
+
+    pub fn playback_one(&self, offset: i32, once: bool, output: Arc<dyn KeyOutput>) {
+        self.spawn(move |player| {
+            loop {
+                player.playback(offset, output.as_ref());
+                if player.control.state() == State::Stop || once {
+                    break;
+                }
+            }
+            player.control.stop();
+        });
+    }
+
+    pub fn playback_list(
+        &self,
+        mut index: usize,
+        dir_path: impl AsRef<Path>,
+        random: bool,
+        output: Arc<dyn KeyOutput>,
+    ) {
+        let path = dir_path.as_ref().to_path_buf();
+        let max = self.midis.read().len();
+        // Files that failed to load in a row, the playlist stops once none of them loads
+        let mut failed = 0;
+        self.spawn(move |player| loop {
+            if index < max {
+                let file = player.midis.read()[index].clone();
+                player.current.store(index);
+                player.observers.publish(PlayerEvent::Advanced {
+                    index,
+                    file: file.clone(),
+                });
+                if player.load(path.join(file)).is_err() {
+                    failed += 1;
+                    if failed >= max || player.control.state() == State::Stop {
+                        player.control.stop();
+                        break;
+                    }
+                    index += 1;
+                    continue;
+                }
+                failed = 0;
+                player.playback(0, output.as_ref());
+                if let State::Stop = player.control.state() {
+                    break;
+                }
+                if random {
+                    let mut rng = rand::rng();
+                    index = rng.random_range(0..max);
+                } else {
+                    index += 1;
+                }
+            } else {
+                index = 0;
+            }
+        });
+    }
+
+    pub fn playback_by(
+        &self,
+        path: impl AsRef<Path>,
+        offset: i32,
+        play_mode: PlayMode,
+        output: Arc<dyn KeyOutput>,
+    ) {
+        match play_mode {
+            PlayMode::Once | PlayMode::OneLoop => {
+                self.playback_one(offset, matches!(play_mode, PlayMode::Once), output);
+            }
+            PlayMode::Loop | PlayMode::Random => {
+                if !self.midis.read().is_empty() {
+                    self.playback_list(
+                        self.current.load(),
+                        path,
+                        play_mode.eq(&PlayMode::Random),
+                        output,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn get_midis_path(&self, path: impl AsRef<Path>) {
+        let Ok(entry) = path.as_ref().read_dir() else {
+            return;
+        };
+        let midis = entry
+            .into_iter()
+            .filter_map(|entry| {
+                let Ok(entry) = entry else {
+                    return None;
+                };
+                if entry.path().extension().unwrap_or_default().eq("mid") {
+                    Some(entry.file_name().to_string_lossy().to_string())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        self.current.store(0);
+        *self.midis.write() = midis;
+    }
+
+    pub fn switch_midi(&self, index: usize, path: impl AsRef<Path>) {
+        self.current.store(index);
+        self.control.stop();
+        self.load(path).ok();
+    }
+}
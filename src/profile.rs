@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::util::VKey;
+
+/// Name of the built-in profile percussion is played with
+pub const DRUMS: &str = "GenShin Drum";
+
+const BUILTIN: &[&str] = &[
+    include_str!("../resources/profiles/genshin.ron"),
+    include_str!("../resources/profiles/vrchat.ron"),
+    include_str!("../resources/profiles/windsong.ron"),
+    include_str!("../resources/profiles/zither.ron"),
+    include_str!("../resources/profiles/vintage.ron"),
+    include_str!("../resources/profiles/drum.ron"),
+    include_str!("../resources/profiles/sky.ron"),
+    include_str!("../resources/profiles/piano.ron"),
+];
+
+/// The keyboard layout of an in-game instrument
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentProfile {
+    pub name: String,
+    /// Note to key bindings, several notes may share the same key
+    pub keys: BTreeMap<i32, VKey>,
+    /// The lowest and highest note that is played, every bound note when unset
+    #[serde(default)]
+    pub range: Option<(i32, i32)>,
+    /// How many keys can be pressed at the same instant, unlimited when unset
+    #[serde(default)]
+    pub max_chord: Option<usize>,
+    /// Milliseconds before the same key can be pressed again
+    #[serde(default)]
+    pub min_interval: u32,
+    /// `min_interval` of a single key
+    #[serde(default)]
+    pub key_interval: BTreeMap<VKey, u32>,
+    /// Text of each key in the numbered sheet
+    #[serde(default)]
+    pub labels: BTreeMap<VKey, String>,
+}
+
+impl Default for InstrumentProfile {
+    fn default() -> Self {
+        Self::from_ron(BUILTIN[0]).unwrap()
+    }
+}
+
+impl InstrumentProfile {
+    pub fn from_ron(s: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(s)
+    }
+
+    #[inline]
+    pub fn key(&self, note: i32) -> Option<VKey> {
+        if let Some((low, high)) = self.range {
+            if !(low..=high).contains(&note) {
+                return None;
+            }
+        }
+        self.keys.get(&note).copied()
+    }
+
+    /// Every note that has a key, from low to high
+    pub fn playable(&self) -> impl DoubleEndedIterator<Item = i32> + '_ {
+        self.keys
+            .keys()
+            .copied()
+            .filter(|note| self.key(*note).is_some())
+    }
+
+    /// The lowest and highest playable note
+    pub fn bounds(&self) -> Option<(i32, i32)> {
+        let mut playable = self.playable();
+        let low = playable.next()?;
+        Some((low, playable.next_back().unwrap_or(low)))
+    }
+
+    /// Nanoseconds before `vk` can be pressed again
+    pub fn interval(&self, vk: VKey) -> u64 {
+        *self.key_interval.get(&vk).unwrap_or(&self.min_interval) as u64 * 1_000_000
+    }
+
+    pub fn label(&self, vk: VKey) -> String {
+        self.labels
+            .get(&vk)
+            .cloned()
+            .unwrap_or_else(|| vk.as_ref().to_lowercase())
+    }
+
+    /// Distinct keys of the layout, from the highest note to the lowest
+    pub fn distinct_keys(&self) -> Vec<VKey> {
+        let mut keys = vec![];
+        self.keys.values().rev().for_each(|vk| {
+            if !keys.contains(vk) {
+                keys.push(*vk);
+            }
+        });
+        keys
+    }
+
+    /// The same layout with some keys replaced by others
+    pub fn remap(&self, remap: &BTreeMap<VKey, VKey>) -> Self {
+        let get = |vk: &VKey| *remap.get(vk).unwrap_or(vk);
+        Self {
+            keys: self
+                .keys
+                .iter()
+                .map(|(note, vk)| (*note, get(vk)))
+                .collect(),
+            key_interval: self
+                .key_interval
+                .iter()
+                .map(|(vk, interval)| (get(vk), *interval))
+                .collect(),
+            labels: self
+                .labels
+                .iter()
+                .map(|(vk, label)| (get(vk), label.clone()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// All known profiles, the built-in ones first
+#[derive(Debug, Clone)]
+pub struct Profiles(Vec<InstrumentProfile>);
+
+impl Profiles {
+    pub fn builtin() -> Self {
+        Self(
+            BUILTIN
+                .iter()
+                .map(|s| InstrumentProfile::from_ron(s).unwrap())
+                .collect(),
+        )
+    }
+
+    /// Adds every `*.ron` profile in `dir`, a profile replaces the one with the same name.
+    /// Returns the files that failed to load.
+    pub fn load_dir(&mut self, dir: impl AsRef<Path>) -> Vec<String> {
+        let Ok(entry) = dir.as_ref().read_dir() else {
+            return vec![];
+        };
+        let mut errors = vec![];
+        entry.flatten().for_each(|entry| {
+            let path = entry.path();
+            if !path.extension().unwrap_or_default().eq("ron") {
+                return;
+            }
+            match std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| InstrumentProfile::from_ron(&s).map_err(|e| e.to_string()))
+            {
+                Ok(profile) => self.insert(profile),
+                Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+            }
+        });
+        errors
+    }
+
+    pub fn insert(&mut self, profile: InstrumentProfile) {
+        match self.0.iter_mut().find(|p| p.name == profile.name) {
+            Some(p) => *p = profile,
+            None => self.0.push(profile),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InstrumentProfile> {
+        self.0
+            .iter()
+            .find(|p| p.name == name)
+            .or_else(|| self.0.iter().find(|p| p.name.eq_ignore_ascii_case(name)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InstrumentProfile> {
+        self.0.iter()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|p| p.name.clone()).collect()
+    }
+}
@@ -0,0 +1,104 @@
+use std::hint::spin_loop;
+use std::time::{Duration, Instant};
+
+use crate::control::Controller;
+
+/// Waits for absolute deadlines in nanoseconds since playback started
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    start: Instant,
+    /// Time before a deadline that is spun instead of slept,
+    /// the OS may oversleep by about this much
+    spin: Duration,
+}
+
+impl Scheduler {
+    pub fn new(spin: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            spin,
+        }
+    }
+
+    /// Continues the clock from `elapsed` nanoseconds, e.g. after a pause
+    pub fn resume(&mut self, elapsed: u64) {
+        self.start = Instant::now() - Duration::from_nanos(elapsed);
+    }
+
+    /// Nanoseconds since the start
+    #[inline]
+    pub fn elapsed(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    /// Sleeps until the spin window before `deadline`, then spins until it is reached.
+    /// Returns how many nanoseconds late it woke up,
+    /// or `None` as soon as `control` has a command.
+    pub fn wait_until(&self, deadline: u64, control: &Controller) -> Option<u64> {
+        let deadline = Duration::from_nanos(deadline);
+        if let Some(rest) = deadline
+            .checked_sub(self.start.elapsed())
+            .and_then(|rest| rest.checked_sub(self.spin))
+        {
+            if control.wait_until(Instant::now() + rest) {
+                return None;
+            }
+        }
+        loop {
+            if control.pending() {
+                return None;
+            }
+            let elapsed = self.start.elapsed();
+            if elapsed >= deadline {
+                return Some((elapsed - deadline).as_nanos() as u64);
+            }
+            spin_loop();
+        }
+    }
+}
+
+/// How late the notes of a playback were sent, in nanoseconds
+#[derive(Debug, Clone, Default)]
+pub struct Lateness {
+    samples: Vec<u64>,
+}
+
+impl Lateness {
+    pub fn record(&mut self, late: u64) {
+        self.samples.push(late);
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean(&self) -> u64 {
+        match self.samples.len() {
+            0 => 0,
+            len => self.samples.iter().sum::<u64>() / len as u64,
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.samples.iter().max().copied().unwrap_or_default()
+    }
+
+    /// The lateness `p` percent of the notes stay under
+    pub fn percentile(&self, p: f32) -> u64 {
+        if self.samples.is_empty() {
+            return 0;
+        }
+        let mut samples = self.samples.clone();
+        samples.sort_unstable();
+        let index = ((samples.len() - 1) as f32 * p.clamp(0.0, 100.0) / 100.0).round() as usize;
+        samples[index]
+    }
+}
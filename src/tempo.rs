@@ -0,0 +1,359 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::midi::Division;
+
+/// The tempo of a file without tempo events, 120 BPM
+pub const DEFAULT_TEMPO_MPQ: u32 = 500000;
+
+/// A tempo taking effect at `tick`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tempo {
+    pub tick: u32,
+    /// Microseconds from the start of the song to `tick`
+    pub micros: f64,
+    /// Microseconds per quarter note
+    pub mpq: u32,
+}
+
+impl Tempo {
+    pub fn bpm(&self) -> f32 {
+        60_000_000.0 / self.mpq.max(1) as f32
+    }
+}
+
+/// A beat of a bar, both counted from 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BarBeat {
+    pub bar: u32,
+    pub beat: u32,
+}
+
+impl Display for BarBeat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.bar, self.beat)
+    }
+}
+
+/// A time signature taking effect at `tick`, the start of bar `bar` counted from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Meter {
+    pub tick: u32,
+    pub bar: u32,
+    /// Beats in a bar
+    pub numerator: u8,
+    /// Note value of a beat, 4 for a quarter note
+    pub denominator: u32,
+}
+
+impl Meter {
+    /// Ticks of a beat with `tpq` ticks per quarter note
+    pub fn beat_ticks(&self, tpq: f32) -> f64 {
+        tpq as f64 * 4.0 / self.denominator.max(1) as f64
+    }
+
+    pub fn bar_ticks(&self, tpq: f32) -> f64 {
+        self.beat_ticks(tpq) * self.numerator.max(1) as f64
+    }
+}
+
+/// Every tempo change of a file, whichever track it is in,
+/// to convert ticks to time for any selection of tracks
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    division: Division,
+    /// Sorted by tick, the first one is always at tick 0
+    changes: Vec<Tempo>,
+    /// Sorted by tick, the first one is always at tick 0
+    meters: Vec<Meter>,
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        Self::new(Division::Metrical(480.0), [], [])
+    }
+}
+
+impl TempoMap {
+    /// Builds the map from `(tick, mpq)` tempo events and `(tick, numerator, denominator)`
+    /// time signatures in any order, the last one read wins when several are at the same tick.
+    /// The denominator is a power of two as stored in the file.
+    pub fn new(
+        division: Division,
+        tempos: impl IntoIterator<Item = (u32, u32)>,
+        meters: impl IntoIterator<Item = (u32, u8, u8)>,
+    ) -> Self {
+        let mut tempos = tempos.into_iter().collect::<Vec<_>>();
+        tempos.sort_by_key(|(tick, _)| *tick);
+
+        let mut changes = vec![Tempo {
+            tick: 0,
+            micros: 0.0,
+            mpq: DEFAULT_TEMPO_MPQ,
+        }];
+        for (tick, mpq) in tempos {
+            let last = changes.last_mut().unwrap();
+            if last.tick == tick {
+                last.mpq = mpq;
+                continue;
+            }
+            let micros = last.micros + Self::span(division, tick - last.tick, last.mpq);
+            changes.push(Tempo { tick, micros, mpq });
+        }
+
+        let mut signatures = meters.into_iter().collect::<Vec<_>>();
+        signatures.sort_by_key(|(tick, _, _)| *tick);
+        let tpq = match division {
+            Division::Metrical(tpq) => tpq,
+            Division::Timecode(_) => 480.0,
+        };
+        let mut meters = vec![Meter {
+            tick: 0,
+            bar: 1,
+            numerator: 4,
+            denominator: 4,
+        }];
+        for (tick, numerator, power) in signatures {
+            let last = meters.last_mut().unwrap();
+            let denominator = 1u32.checked_shl(power as u32).unwrap_or(4);
+            if last.tick == tick {
+                last.numerator = numerator;
+                last.denominator = denominator;
+                continue;
+            }
+            // A signature in the middle of a bar starts a new one
+            let bars = ((tick - last.tick) as f64 / last.bar_ticks(tpq) - 1e-6).ceil() as u32;
+            let bar = last.bar + bars;
+            meters.push(Meter {
+                tick,
+                bar,
+                numerator,
+                denominator,
+            });
+        }
+        Self {
+            division,
+            changes,
+            meters,
+        }
+    }
+
+    pub fn division(&self) -> Division {
+        self.division
+    }
+
+    /// The tempo changes in order, starting with the tempo at tick 0
+    pub fn changes(&self) -> &[Tempo] {
+        &self.changes
+    }
+
+    /// The time signatures in order, starting with the one at tick 0
+    pub fn meters(&self) -> &[Meter] {
+        &self.meters
+    }
+
+    /// The time signature of the bar `tick` is in
+    pub fn meter_at(&self, tick: u32) -> Meter {
+        let index = self.meters.partition_point(|meter| meter.tick <= tick);
+        self.meters[index.saturating_sub(1)]
+    }
+
+    /// The tick `beat` of `bar` starts at, both counted from 1,
+    /// `None` for timecode files which have no beats
+    pub fn tick_at_bar(&self, bar: u32, beat: u32) -> Option<u32> {
+        let Division::Metrical(tpq) = self.division else {
+            return None;
+        };
+        let bar = bar.max(1);
+        let index = self.meters.partition_point(|meter| meter.bar <= bar);
+        let meter = self.meters[index.saturating_sub(1)];
+        let ticks = (bar - meter.bar) as f64 * meter.bar_ticks(tpq)
+            + beat.saturating_sub(1) as f64 * meter.beat_ticks(tpq);
+        Some(
+            meter
+                .tick
+                .saturating_add(ticks.round().min(u32::MAX as f64) as u32),
+        )
+    }
+
+    /// Microseconds of a beat at `tick`, a quarter note at 120 BPM for timecode files
+    pub fn beat_micros(&self, tick: u32) -> f64 {
+        match self.division {
+            Division::Metrical(tpq) => {
+                self.meter_at(tick).beat_ticks(tpq) * self.tempo_at(tick).mpq as f64 / tpq as f64
+            }
+            Division::Timecode(_) => DEFAULT_TEMPO_MPQ as f64,
+        }
+    }
+
+    /// The bar and beat `tick` falls in, `None` for timecode files which have no beats
+    pub fn bar_at(&self, tick: u32) -> Option<BarBeat> {
+        let Division::Metrical(tpq) = self.division else {
+            return None;
+        };
+        let meter = self.meter_at(tick);
+        let beats = ((tick - meter.tick) as f64 / meter.beat_ticks(tpq) + 1e-6).floor() as u32;
+        let numerator = meter.numerator.max(1) as u32;
+        Some(BarBeat {
+            bar: meter.bar + beats / numerator,
+            beat: beats % numerator + 1,
+        })
+    }
+
+    /// The tempo `tick` is played at
+    pub fn tempo_at(&self, tick: u32) -> Tempo {
+        let index = self.changes.partition_point(|change| change.tick <= tick);
+        self.changes[index.saturating_sub(1)]
+    }
+
+    /// Microseconds from the start of the song to `tick`
+    pub fn micros_at(&self, tick: u32) -> f64 {
+        match self.division {
+            Division::Metrical(_) => {
+                let tempo = self.tempo_at(tick);
+                tempo.micros + Self::span(self.division, tick - tempo.tick, tempo.mpq)
+            }
+            Division::Timecode(_) => Self::span(self.division, tick, 0),
+        }
+    }
+
+    /// The first tick at or after `micros` from the start of the song
+    pub fn tick_at(&self, micros: f64) -> u32 {
+        let micros = micros.max(0.0);
+        let (tick, micros, per_tick) = match self.division {
+            Division::Metrical(tpq) => {
+                let index = self
+                    .changes
+                    .partition_point(|change| change.micros <= micros);
+                let tempo = self.changes[index.saturating_sub(1)];
+                (
+                    tempo.tick,
+                    micros - tempo.micros,
+                    tempo.mpq as f64 / tpq as f64,
+                )
+            }
+            Division::Timecode(tps) => (0, micros, 1_000_000.0 / tps as f64),
+        };
+        if per_tick <= 0.0 {
+            return tick;
+        }
+        // Rounding errors must not push an exact tick to the next one
+        let ticks = (micros / per_tick - 1e-6).ceil().max(0.0);
+        tick.saturating_add(ticks.min(u32::MAX as f64) as u32)
+    }
+
+    /// Microseconds `ticks` last at `mpq`, which timecode files ignore
+    fn span(division: Division, ticks: u32, mpq: u32) -> f64 {
+        match division {
+            Division::Metrical(tpq) => ticks as f64 * mpq as f64 / tpq as f64,
+            Division::Timecode(tps) => ticks as f64 * 1_000_000.0 / tps as f64,
+        }
+    }
+}
+
+/// Parses `mm:ss`, `hh:mm:ss` or plain seconds, fractions allowed, into microseconds
+pub fn parse_time(s: &str) -> Option<usize> {
+    let seconds = s.trim().split(':').try_fold(0.0, |total: f64, part| {
+        Some(total * 60.0 + part.parse::<f64>().ok()?)
+    })?;
+    (seconds.is_finite() && seconds >= 0.0).then_some((seconds * 1_000_000.0) as usize)
+}
+
+/// Parses `bar` or `bar.beat`, both counted from 1
+pub fn parse_bar(s: &str) -> Option<(u32, u32)> {
+    let (bar, beat) = s.trim().split_once('.').unwrap_or((s.trim(), "1"));
+    let (bar, beat) = (bar.parse().ok()?, beat.parse().ok()?);
+    (bar > 0 && beat > 0).then_some((bar, beat))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(tempos: &[(u32, u32)]) -> TempoMap {
+        TempoMap::new(Division::Metrical(480.0), tempos.iter().copied(), [])
+    }
+
+    #[test]
+    fn default_tempo() {
+        let map = map(&[]);
+        assert_eq!(map.changes().len(), 1);
+        assert_eq!(map.micros_at(480), 500_000.0);
+        assert_eq!(map.tempo_at(0).bpm(), 120.0);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let map = map(&[(960, 250_000), (0, 500_000)]);
+        assert_eq!(map.micros_at(960), 1_000_000.0);
+        assert_eq!(map.micros_at(1440), 1_250_000.0);
+        assert_eq!(map.tempo_at(959).mpq, 500_000);
+        assert_eq!(map.tempo_at(960).mpq, 250_000);
+    }
+
+    #[test]
+    fn last_tempo_at_a_tick_wins() {
+        let map = map(&[(0, 500_000), (0, 1_000_000)]);
+        assert_eq!(map.changes().len(), 1);
+        assert_eq!(map.micros_at(480), 1_000_000.0);
+    }
+
+    #[test]
+    fn tick_at_inverts_micros_at() {
+        let map = map(&[(960, 250_000)]);
+        for tick in [0, 1, 479, 960, 961, 1200, 10_000] {
+            assert_eq!(map.tick_at(map.micros_at(tick)), tick);
+        }
+        // Between two ticks the next one is taken
+        assert_eq!(map.tick_at(1.0), 1);
+        assert_eq!(map.tick_at(-5.0), 0);
+    }
+
+    #[test]
+    fn timecode_ignores_tempo() {
+        let map = TempoMap::new(Division::Timecode(100.0), [(0, 250_000)], []);
+        assert_eq!(map.micros_at(50), 500_000.0);
+        assert_eq!(map.tick_at(500_000.0), 50);
+    }
+
+    fn meters(meters: &[(u32, u8, u8)]) -> TempoMap {
+        TempoMap::new(Division::Metrical(480.0), [], meters.iter().copied())
+    }
+
+    #[test]
+    fn bars_of_common_time() {
+        let map = meters(&[]);
+        assert_eq!(map.bar_at(0), Some(BarBeat { bar: 1, beat: 1 }));
+        assert_eq!(map.bar_at(479), Some(BarBeat { bar: 1, beat: 1 }));
+        assert_eq!(map.bar_at(480), Some(BarBeat { bar: 1, beat: 2 }));
+        assert_eq!(map.bar_at(1920), Some(BarBeat { bar: 2, beat: 1 }));
+        assert_eq!(map.tick_at_bar(2, 3), Some(2880));
+    }
+
+    #[test]
+    fn meter_changes() {
+        // Two bars of 3/4, then 6/8
+        let map = meters(&[(0, 3, 2), (2880, 6, 3)]);
+        assert_eq!(map.meter_at(2880).bar, 3);
+        assert_eq!(map.bar_at(1440), Some(BarBeat { bar: 2, beat: 1 }));
+        assert_eq!(map.bar_at(2880 + 240), Some(BarBeat { bar: 3, beat: 2 }));
+        assert_eq!(map.bar_at(2880 + 1440), Some(BarBeat { bar: 4, beat: 1 }));
+        assert_eq!(map.tick_at_bar(3, 1), Some(2880));
+        assert_eq!(map.tick_at_bar(4, 2), Some(2880 + 1440 + 240));
+        assert_eq!(map.beat_micros(2880), 250_000.0);
+    }
+
+    #[test]
+    fn meter_in_the_middle_of_a_bar_starts_one() {
+        let map = meters(&[(2400, 3, 2)]);
+        assert_eq!(map.bar_at(2399), Some(BarBeat { bar: 2, beat: 1 }));
+        assert_eq!(map.bar_at(2400), Some(BarBeat { bar: 3, beat: 1 }));
+        assert_eq!(map.tick_at_bar(3, 1), Some(2400));
+    }
+
+    #[test]
+    fn timecode_has_no_bars() {
+        let map = TempoMap::new(Division::Timecode(100.0), [], [(0, 3, 2)]);
+        assert_eq!(map.bar_at(100), None);
+        assert_eq!(map.tick_at_bar(2, 1), None);
+    }
+}
@@ -1,12 +1,12 @@
 use std::cmp::Ordering;
 use std::path::Path;
 
-use eframe::egui::{Button, Context, Separator, Ui};
+use eframe::egui::{Button, Context, DragValue, Separator, Ui};
 use eframe::{egui, App, Frame};
 use strum::IntoEnumIterator;
 
-use crate::maps::MAP;
-use crate::midi::{is_playing, CURRENT_MIDI};
+use crate::analysis::{note_name, pitch_class_name, Stat};
+use crate::midi::{Percussion, Split, TrackMix};
 use crate::ui::play::Play;
 use crate::util::VKey;
 
@@ -16,6 +16,10 @@ pub trait View {
     fn ui(&mut self, ui: &mut Ui);
 }
 
+fn stat_text(stat: &Stat) -> String {
+    format!("{:.2}% ({}/{})", stat.rate() * 100.0, stat.hits, stat.total)
+}
+
 impl App for Play {
     fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
         ctx.request_repaint();
@@ -26,18 +30,125 @@ impl App for Play {
             .open(&mut self.tracks_enable)
             .show(ctx, |ui| {
                 if tracks_enable {
+                    ui.horizontal(|ui| {
+                        ui.label("拆分音轨:");
+                        let split = self.config.split;
+                        ui.radio_value(&mut self.config.split, Split::Off, "不拆分");
+                        ui.radio_value(&mut self.config.split, Split::Channel, "按通道");
+                        ui.radio_value(&mut self.config.split, Split::Program, "按通道和乐器");
+                        if self.config.split != split {
+                            if self.player.is_playing() {
+                                self.config.split = split;
+                            } else {
+                                // The saved mix belongs to the tracks split the other way
+                                if let Some(name) = self.player.midi.name.read().as_ref() {
+                                    self.player.mixes.write().remove(name);
+                                }
+                                self.player.midi.set_split(self.config.split);
+                                self.notify_merge = true;
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("打击乐:");
+                        let percussion = self.config.percussion;
+                        ui.radio_value(&mut self.config.percussion, Percussion::Skip, "跳过");
+                        ui.radio_value(
+                            &mut self.config.percussion,
+                            Percussion::Melodic,
+                            "当作旋律",
+                        );
+                        ui.radio_value(&mut self.config.percussion, Percussion::Drums, "鼓映射");
+                        if self.config.percussion != percussion {
+                            self.player.midi.percussion.store(self.config.percussion);
+                            self.notify_merge = true;
+                        }
+                        if self.config.percussion == Percussion::Drums {
+                            let drums = self.config.drums.clone();
+                            egui::ComboBox::from_id_salt("Drums")
+                                .selected_text(self.config.drums.as_str())
+                                .show_ui(ui, |ui| {
+                                    for name in self.profiles.names() {
+                                        let text = name.clone();
+                                        ui.selectable_value(&mut self.config.drums, name, text);
+                                    }
+                                });
+                            if self.config.drums != drums {
+                                if let Some(profile) = self.profiles.get(&self.config.drums) {
+                                    *self.player.midi.drums.write() = profile.clone();
+                                    self.notify_merge = true;
+                                }
+                            }
+                        }
+                    });
+                    let percussion = (0..self.player.midi.track_num.read().len())
+                        .map(|index| self.player.midi.is_percussion(index))
+                        .collect::<Vec<_>>();
+                    let mut mixed = false;
                     egui::ScrollArea::both()
                         .auto_shrink([true, true])
                         .show(ui, |ui| {
-                            for (enable, index, name) in self.midi.track_num.write().iter_mut() {
-                                if ui
-                                    .checkbox(enable, format!("Track {}: {}", index, name))
-                                    .changed()
+                            let mut mixer = self.player.midi.mixer.write();
+                            egui::Grid::new("Mixer").striped(true).show(ui, |ui| {
+                                for ((enable, index, name), mix) in self
+                                    .player
+                                    .midi
+                                    .track_num
+                                    .write()
+                                    .iter_mut()
+                                    .zip(mixer.iter_mut())
                                 {
-                                    self.notify_merge = true;
+                                    let drums = if percussion[*index] {
+                                        " [打击乐]"
+                                    } else {
+                                        ""
+                                    };
+                                    if ui
+                                        .checkbox(
+                                            enable,
+                                            format!("Track {}: {}{}", index, name, drums),
+                                        )
+                                        .changed()
+                                    {
+                                        self.notify_merge = true;
+                                    }
+                                    let before = *mix;
+                                    ui.toggle_value(&mut mix.mute, "静音");
+                                    ui.toggle_value(&mut mix.solo, "独奏");
+                                    if ui.button("-8").on_hover_text("降八度").clicked() {
+                                        mix.transpose -= 12;
+                                    }
+                                    ui.add(
+                                        DragValue::new(&mut mix.transpose)
+                                            .range(-48..=48)
+                                            .suffix(" 半音"),
+                                    );
+                                    if ui.button("+8").on_hover_text("升八度").clicked() {
+                                        mix.transpose += 12;
+                                    }
+                                    mix.transpose = mix.transpose.clamp(-48, 48);
+                                    ui.add(
+                                        DragValue::new(&mut mix.min_velocity)
+                                            .range(0..=127)
+                                            .prefix("最小力度: "),
+                                    );
+                                    mixed |= *mix != before;
+                                    ui.end_row();
                                 }
-                            }
+                            });
                         });
+                    if mixed {
+                        self.notify_merge = true;
+                        if let Some(name) = self.player.midi.name.read().clone() {
+                            let mixer = self.player.midi.mixer.read();
+                            let mut mixes = self.player.mixes.write();
+                            if mixer.iter().all(TrackMix::is_default) {
+                                mixes.remove(&name);
+                            } else {
+                                mixes.insert(name, mixer.clone());
+                            }
+                        }
+                    }
                 }
             });
         let pitch_enable = self.pitch_enable;
@@ -47,18 +158,30 @@ impl App for Play {
             .show(ctx, |ui| {
                 if pitch_enable {
                     if ui.button("还原音调").clicked() {
-                        self.midi.track_keys.write().iter_mut().for_each(|keys| {
-                            keys.iter_mut().for_each(|key| {
-                                key.key = key.backup;
-                                key.real = 0;
+                        self.player
+                            .midi
+                            .track_keys
+                            .write()
+                            .iter_mut()
+                            .for_each(|keys| {
+                                keys.iter_mut().for_each(|key| {
+                                    key.key = key.backup;
+                                    key.real = 0;
+                                });
                             });
-                        });
+                        self.notify_merge = true;
+                    }
+                    if ui.button("按音轨自动调音").clicked() {
+                        self.player
+                            .midi
+                            .auto_tune_tracks(-self.tune_range..=self.tune_range, self.offset);
                         self.notify_merge = true;
                     }
                     egui::ScrollArea::both()
                         .auto_shrink([true, true])
                         .show(ui, |ui| {
-                            for (index, keys) in self.midi.track_keys.write().iter_mut().enumerate()
+                            for (index, keys) in
+                                self.player.midi.track_keys.write().iter_mut().enumerate()
                             {
                                 ui.collapsing(format!("Track {index}"), |ui| {
                                     ui.horizontal(|ui| {
@@ -92,64 +215,141 @@ impl App for Play {
                         });
                 }
             });
-        if self.notify_merge && !is_playing() {
-            self.midi
-                .merge_tracks(&self.midi.current_range(), self.offset);
+        if self.notify_merge && !self.player.is_playing() {
+            self.player
+                .midi
+                .merge_tracks(&self.player.midi.current_range(), self.offset);
             self.notify_merge = false;
+            self.candidates.clear();
+            self.analysis = None;
         }
+        let mut notify_profile = false;
         egui::Window::new("按键映射")
             .scroll([true, true])
             .open(&mut self.map_enable)
-            .show(ctx, |ui| unsafe {
-                for (i, level) in ['+', ' ', '-'].into_iter().enumerate() {
-                    ui.separator();
-                    for key in 0..7 {
-                        let id = i * 7 + key;
-                        egui::ComboBox::from_label(format!("{}{}", level, key + 1))
-                            .selected_text(MAP[id].as_ref())
-                            .show_ui(ui, |ui| {
-                                VKey::iter()
-                                    .filter(|k| {
-                                        self.config.function_key.pause.ne(k)
-                                            && self.config.function_key.play.ne(k)
-                                            && self.config.function_key.stop.ne(k)
-                                    })
-                                    .for_each(|key| {
-                                        ui.selectable_value(&mut MAP[id], key, key.as_ref());
-                                    });
-                            });
+            .show(ctx, |ui| {
+                let Some(profile) = self.profiles.get(&self.profile).cloned() else {
+                    return;
+                };
+                if ui.button("还原按键").clicked() {
+                    self.config.keys.remove(&profile.name);
+                    notify_profile = true;
+                }
+                ui.separator();
+                let remap = self.config.keys.entry(profile.name.clone()).or_default();
+                for origin in profile.distinct_keys() {
+                    let mut current = *remap.get(&origin).unwrap_or(&origin);
+                    egui::ComboBox::from_label(profile.label(origin))
+                        .selected_text(current.as_ref())
+                        .show_ui(ui, |ui| {
+                            VKey::iter()
+                                .filter(|k| {
+                                    self.config.function_key.pause.ne(k)
+                                        && self.config.function_key.play.ne(k)
+                                        && self.config.function_key.stop.ne(k)
+                                        && self.config.function_key.mark.ne(k)
+                                })
+                                .for_each(|key| {
+                                    ui.selectable_value(&mut current, key, key.as_ref());
+                                });
+                        });
+                    if current != *remap.get(&origin).unwrap_or(&origin) {
+                        if current == origin {
+                            remap.remove(&origin);
+                        } else {
+                            remap.insert(origin, current);
+                        }
+                        notify_profile = true;
                     }
                 }
             });
+        if notify_profile {
+            self.apply_profile();
+        }
+
+        egui::Window::new("命中分析")
+            .scroll([true, true])
+            .open(&mut self.analysis_enable)
+            .show(ctx, |ui| {
+                if self
+                    .analysis
+                    .as_ref()
+                    .is_none_or(|(offset, _)| *offset != self.offset)
+                {
+                    self.analysis = Some((self.offset, self.player.midi.analyze(self.offset)));
+                }
+                let Some((_, analysis)) = &self.analysis else {
+                    return;
+                };
+                ui.label(format!("命中率: {}", stat_text(&analysis.all)));
+                ui.label(format!(
+                    "高于音域: {} 低于音域: {}",
+                    analysis.above, analysis.below
+                ));
+                ui.collapsing("按音轨", |ui| {
+                    egui::Grid::new("Tracks").striped(true).show(ui, |ui| {
+                        for (index, name, stat) in analysis.tracks.iter() {
+                            ui.label(format!("Track {}: {}", index, name));
+                            ui.label(stat_text(stat));
+                            ui.end_row();
+                        }
+                    });
+                });
+                ui.collapsing("按八度", |ui| {
+                    egui::Grid::new("Octaves").striped(true).show(ui, |ui| {
+                        for (octave, stat) in analysis.octaves.iter() {
+                            ui.label(format!("{}", octave));
+                            ui.label(stat_text(stat));
+                            ui.end_row();
+                        }
+                    });
+                });
+                ui.collapsing("按音名", |ui| {
+                    egui::Grid::new("PitchClasses")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for (class, stat) in analysis.pitch_classes.iter().enumerate() {
+                                if stat.total > 0 {
+                                    ui.label(pitch_class_name(class));
+                                    ui.label(stat_text(stat));
+                                    ui.end_row();
+                                }
+                            }
+                        });
+                });
+                ui.collapsing("无法演奏最多的音", |ui| {
+                    for (note, count) in analysis.missing.iter() {
+                        ui.label(format!("{}: {}次", note_name(*note), count));
+                    }
+                });
+            });
 
         egui::Window::new("MIDI列表")
             .scroll([true, true])
             .open(&mut self.dir_enable)
             .show(ctx, |ui| {
-                let midis = self.midi.midis.read();
+                let midis = self.player.midis.read();
                 if midis.is_empty() {
                     return;
                 }
                 for (index, midi_file) in midis.iter().enumerate() {
                     ui.horizontal(|ui| {
-                        let cond = CURRENT_MIDI.load().eq(&index);
+                        let cond = self.player.current.load().eq(&index);
                         if ui.button("▶").clicked() {
-                            let midi = self.midi.clone();
-                            midi.switch_midi(
+                            self.player.switch_midi(
                                 index,
                                 Path::new(self.config.midi_dir.0.read().as_str()).join(midi_file),
                             );
-                            midi.playback_by(
+                            self.player.playback_by(
                                 self.config.midi_dir.0.read().as_str(),
                                 self.offset,
                                 self.play_mode,
-                                self.mode,
+                                self.output.clone(),
                             );
                         }
                         let file = ui.add(Button::selectable(cond, midi_file));
                         if file.clicked() {
-                            let midi = self.midi.clone();
-                            midi.switch_midi(
+                            self.player.switch_midi(
                                 index,
                                 Path::new(self.config.midi_dir.0.read().as_str()).join(midi_file),
                             );
@@ -160,7 +360,8 @@ impl App for Play {
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
-        self.config.map = unsafe { MAP };
+        self.config.keys.retain(|_, remap| !remap.is_empty());
+        self.config.mixes = self.player.mixes.read().clone();
         ron::to_string(&self.config)
             .inspect(|config| {
                 std::fs::write("config.ron", config).ok();
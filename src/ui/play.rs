@@ -1,43 +1,68 @@
-#![allow(static_mut_refs)]
-
+use std::collections::BTreeMap;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use eframe::egui::{Slider, Ui};
+use crossbeam::channel::Receiver;
+use eframe::egui::{Color32, DragValue, Slider, TextEdit, Ui};
 use eframe::{egui, CreationContext};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 
+use crate::analysis::Analysis;
+use crate::chord::{ChordLimit, Strategy};
+use crate::control::Seek;
 use crate::font::load_fonts;
-use crate::maps::{is_pressed, MAP};
-use crate::midi::{Midi, State, PLAYING, SPEED, STATE};
+use crate::ghost::GhostFilter;
+use crate::maps::{is_pressed, Fold};
+use crate::melody::{Skyline, Voice};
+use crate::midi::{Division, Event, Percussion, Split, State, Sustain, TrackMix};
+use crate::observe::PlayerEvent;
+use crate::output::{Backend, KeyOutput};
+use crate::player::{LoopRegion, Player, Settings, DEFAULT_SPIN};
+use crate::profile::{Profiles, DRUMS};
+use crate::tempo::{parse_bar, parse_time};
 use crate::ui::View;
 use crate::util::VKey;
-use crate::{COUNT, LOCAL, POOL, TIME_SHIFT};
+use crate::POOL;
 
 #[derive(Debug, Clone)]
 pub struct Play {
-    pub midi: Midi,
+    pub player: Player,
     pub speed: f32,
-    pub mode: Mode,
+    pub profiles: Profiles,
+    pub profile: String,
     pub play_mode: PlayMode,
     pub state: &'static str,
     pub tracks_enable: bool,
     pub pitch_enable: bool,
     pub map_enable: bool,
+    pub analysis_enable: bool,
     pub dir_enable: bool,
     pub offset: i32,
     pub notify_merge: bool,
     pub config: Config,
     pub control_key: ControlKey,
     pub progress: usize,
+    pub output: Arc<dyn KeyOutput>,
+    pub tune_range: i32,
+    pub candidates: Vec<(i32, f32)>,
+    /// The analysis of the merged song and the offset it was made at,
+    /// cleared whenever the song or the profile changes
+    pub analysis: Option<(i32, Analysis)>,
+    /// Time or bar typed in to jump to
+    pub jump: String,
+    pub ab: LoopInput,
+    pub events: Receiver<PlayerEvent>,
+    /// The last error of the player
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct ControlKey {
     pub add: bool,
     pub sub: bool,
+    pub mark: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -45,6 +70,13 @@ pub struct FunctionKey {
     pub play: VKey,
     pub pause: VKey,
     pub stop: VKey,
+    /// Marks A, then B of the loop at the current position
+    #[serde(default = "default_mark")]
+    pub mark: VKey,
+}
+
+fn default_mark() -> VKey {
+    VKey::F12
 }
 
 impl Default for FunctionKey {
@@ -53,14 +85,56 @@ impl Default for FunctionKey {
             play: VKey::Space,
             pause: VKey::BackSpace,
             stop: VKey::Control,
+            mark: default_mark(),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum Mode {
-    GenShin,
-    VRChat,
+impl FunctionKey {
+    /// Moves the function keys off the keys `bound` to notes to free keys,
+    /// `true` when any of them moved
+    pub fn clear_of(&mut self, bound: &[VKey]) -> bool {
+        let before = [self.play, self.pause, self.stop, self.mark];
+        let mut keys = before;
+        for index in 0..keys.len() {
+            if bound.contains(&keys[index]) {
+                if let Some(free) = VKey::iter().find(|k| !bound.contains(k) && !keys.contains(k)) {
+                    keys[index] = free;
+                }
+            }
+        }
+        [self.play, self.pause, self.stop, self.mark] = keys;
+        keys != before
+    }
+}
+
+/// The A/B loop being edited
+#[derive(Debug, Clone, Default)]
+pub struct LoopInput {
+    /// Microseconds from the start of the song to A and B
+    pub a: Option<usize>,
+    pub b: Option<usize>,
+    /// Time or bar typed in for A or B
+    pub text: String,
+    pub by_bar: bool,
+    /// Passes of the passage, it loops forever at 0
+    pub times: u32,
+    /// Beats waited before each pass
+    pub count_in: u32,
+    /// Percent of the speed taken off on every repetition
+    pub slow_down: f32,
+    /// Repetitions of the current playback
+    pub repeat: u32,
+}
+
+impl LoopInput {
+    pub fn region(&self) -> Option<LoopRegion> {
+        let mut region = LoopRegion::new(self.a?, self.b?)?;
+        region.times = (self.times > 0).then_some(self.times);
+        region.count_in = self.count_in;
+        region.slow_down = self.slow_down / 100.0;
+        Some(region)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -72,33 +146,96 @@ pub enum PlayMode {
 }
 
 impl Play {
-    pub fn new(cc: &CreationContext) -> Self {
+    pub fn new(cc: &CreationContext, profiles: Profiles) -> Self {
         load_fonts(&cc.egui_ctx);
+        let player = Player::new();
         Self {
-            midi: Midi::new(),
+            events: player.subscribe(),
+            player,
             speed: 1.0,
-            mode: Mode::GenShin,
+            profile: profiles.names()[0].clone(),
+            profiles,
             play_mode: PlayMode::Once,
             state: "已停止",
             tracks_enable: false,
             pitch_enable: false,
             map_enable: false,
+            analysis_enable: false,
             dir_enable: false,
             offset: 0,
             notify_merge: false,
             config: Config::default(),
             control_key: ControlKey::default(),
             progress: 0,
+            output: Backend::default().open(),
+            tune_range: 24,
+            jump: String::new(),
+            ab: LoopInput::default(),
+            candidates: vec![],
+            analysis: None,
+            error: None,
+        }
+    }
+
+    /// Loads the selected profile with the keys replaced by the user into the player
+    pub fn apply_profile(&mut self) {
+        let profile = self
+            .profiles
+            .get(&self.profile)
+            .cloned()
+            .unwrap_or_default();
+        self.profile = profile.name.clone();
+        *self.player.midi.profile.write() = match self.config.keys.get(&profile.name) {
+            Some(remap) => profile.remap(remap),
+            None => profile,
+        };
+        let bound = self.player.midi.profile.read().distinct_keys();
+        if self.config.function_key.clear_of(&bound) {
+            self.error = Some(format!(
+                "功能键与 {} 的按键冲突, 已改为其他按键",
+                self.profile
+            ));
         }
+        self.player
+            .midi
+            .hit_rate
+            .store(self.player.midi.detect(self.offset));
+        self.candidates.clear();
+        self.analysis = None;
+        self.notify_merge = true;
+    }
+
+    /// The loop point typed in, or the current position when nothing is typed
+    fn loop_point(&mut self) -> Option<usize> {
+        let text = self.ab.text.trim();
+        let seek = if text.is_empty() {
+            if self.player.control.state() == State::Stop {
+                return None;
+            }
+            Seek::Event(self.progress)
+        } else if self.ab.by_bar {
+            let Some((bar, beat)) = parse_bar(text) else {
+                self.error = Some(format!("无法识别的小节: {text}"));
+                return None;
+            };
+            Seek::Bar { bar, beat }
+        } else {
+            let Some(micros) = parse_time(text) else {
+                self.error = Some(format!("无法识别的时间: {text}"));
+                return None;
+            };
+            Seek::Time(micros)
+        };
+        self.player.midi.time_of(seek)
     }
 
     fn select_dir(&self) {
         let dir = self.config.midi_dir.0.clone();
-        let midi = self.midi.clone();
+        let player = self.player.clone();
         POOL.spawn(move || {
             if let Some(path) = rfd::FileDialog::new().pick_folder() {
                 *dir.write() = path.to_string_lossy().to_string();
-                midi.get_midis_path(path);
+                player.get_midis_path(path);
             }
         });
     }
@@ -111,7 +248,48 @@ pub struct MidiDir(pub Arc<RwLock<String>>);
 pub struct Config {
     pub midi_dir: MidiDir,
     pub function_key: FunctionKey,
-    pub map: [VKey; 21],
+    /// The Genshin key map of older versions, only read to migrate it into `keys`
+    #[serde(default, skip_serializing)]
+    pub map: Vec<VKey>,
+    /// Keys replaced by the user for each profile
+    #[serde(default)]
+    pub keys: BTreeMap<String, BTreeMap<VKey, VKey>>,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub sustain: Sustain,
+    #[serde(default)]
+    pub fold: Fold,
+    #[serde(default)]
+    pub chord: ChordLimit,
+    #[serde(default)]
+    pub ghost: GhostFilter,
+    #[serde(default)]
+    pub melody: Skyline,
+    /// Microseconds before each note that are spun instead of slept
+    #[serde(default = "default_spin")]
+    pub spin: u32,
+    /// The track mix of each song, by file name
+    #[serde(default)]
+    pub mixes: BTreeMap<String, Vec<TrackMix>>,
+    #[serde(default)]
+    pub split: Split,
+    #[serde(default)]
+    pub percussion: Percussion,
+    /// The profile percussion is played with
+    #[serde(default = "default_drums")]
+    pub drums: String,
+    /// Exported sheets put every bar on its own line
+    #[serde(default)]
+    pub sheet_bars: bool,
+}
+
+fn default_drums() -> String {
+    DRUMS.to_string()
+}
+
+fn default_spin() -> u32 {
+    DEFAULT_SPIN
 }
 
 impl Serialize for MidiDir {
@@ -133,50 +311,189 @@ impl<'de> Deserialize<'de> for MidiDir {
     }
 }
 
+impl Config {
+    /// Moves the key map of older versions into `keys`
+    pub fn migrate(&mut self) {
+        const LYRE: [VKey; 21] = [
+            VKey::Q,
+            VKey::W,
+            VKey::E,
+            VKey::R,
+            VKey::T,
+            VKey::Y,
+            VKey::U,
+            VKey::A,
+            VKey::S,
+            VKey::D,
+            VKey::F,
+            VKey::G,
+            VKey::H,
+            VKey::J,
+            VKey::Z,
+            VKey::X,
+            VKey::C,
+            VKey::V,
+            VKey::B,
+            VKey::N,
+            VKey::M,
+        ];
+
+        let map = std::mem::take(&mut self.map);
+        if map.len() != LYRE.len() || self.keys.contains_key("GenShin") {
+            return;
+        }
+        let remap = LYRE
+            .into_iter()
+            .zip(map)
+            .filter(|(from, to)| from != to)
+            .collect::<BTreeMap<_, _>>();
+        if !remap.is_empty() {
+            self.keys.insert("GenShin".to_string(), remap);
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             midi_dir: MidiDir(Arc::new(RwLock::new(String::new()))),
             function_key: Default::default(),
-            map: unsafe { MAP },
+            map: vec![],
+            keys: BTreeMap::new(),
+            backend: Backend::default(),
+            sustain: Sustain::default(),
+            fold: Fold::default(),
+            chord: ChordLimit::default(),
+            ghost: GhostFilter::default(),
+            melody: Skyline::default(),
+            spin: DEFAULT_SPIN,
+            mixes: BTreeMap::new(),
+            split: Split::default(),
+            percussion: Percussion::default(),
+            drums: default_drums(),
+            sheet_bars: false,
         }
     }
 }
 
 impl View for Play {
     fn ui(&mut self, ui: &mut Ui) {
+        for event in self.events.try_iter() {
+            match event {
+                PlayerEvent::Note {
+                    index: position, ..
+                }
+                | PlayerEvent::Seeked { position }
+                | PlayerEvent::Paused { position }
+                | PlayerEvent::Resumed { position } => self.progress = position,
+                PlayerEvent::Loaded { .. } => {
+                    self.progress = 0;
+                    self.analysis = None;
+                    self.error = None;
+                    self.ab.a = None;
+                    self.ab.b = None;
+                }
+                PlayerEvent::Finished { .. } => {
+                    self.progress = 0;
+                    self.ab.repeat = 0;
+                }
+                PlayerEvent::Looped { repeat, .. } => self.ab.repeat = repeat,
+                PlayerEvent::Error(error) => self.error = Some(error),
+                PlayerEvent::Advanced { .. } => {}
+            }
+        }
         ui.vertical_centered(|ui| ui.heading("Lyred"));
         ui.separator();
         ui.horizontal(|ui| {
             if ui.button("选择MIDI文件").clicked() {
-                STATE.store(State::Stop);
+                self.player.control.stop();
                 self.offset = 0;
-                self.midi.clone().init();
+                self.player.clone().init();
             }
             if ui.button("选择MIDI目录").clicked() {
-                STATE.store(State::Stop);
+                self.player.control.stop();
                 self.offset = 0;
                 self.select_dir();
             }
             ui.toggle_value(&mut self.dir_enable, "MIDI列表");
-            if ui.button("从MIDI转换").clicked() {
-                if let Some(name) = self.midi.name.read().as_ref() {
-                    self.midi.clone().convert_from_midi(name.to_string());
+            if ui.button("从MIDI转换").clicked() && !self.player.is_playing() {
+                if let Some(name) = self.player.midi.name.read().as_ref() {
+                    self.player.midi.clone().convert_from_midi(
+                        name.to_string(),
+                        self.offset,
+                        self.config.sheet_bars,
+                    );
                 }
             }
+            ui.checkbox(&mut self.config.sheet_bars, "按小节换行");
         });
-        if let Some(name) = self.midi.name.read().as_ref() {
+        if let Some(name) = self.player.midi.name.read().as_ref() {
             ui.label(format!("当前文件: {}", name));
+            let tempo = self.player.midi.tempo.read();
+            if let Division::Metrical(_) = tempo.division() {
+                let changes = tempo.changes();
+                let (low, high) = changes.iter().fold((f32::MAX, 0.0f32), |(low, high), t| {
+                    (low.min(t.bpm()), high.max(t.bpm()))
+                });
+                let text = if changes.len() > 1 {
+                    format!("速度: {low:.0}~{high:.0} BPM ({}次变速)", changes.len() - 1)
+                } else {
+                    format!("速度: {high:.0} BPM")
+                };
+                ui.horizontal(|ui| {
+                    ui.label(text).on_hover_ui(|ui| {
+                        for change in changes {
+                            ui.label(format!(
+                                "{:02}:{:02} {:.0} BPM",
+                                change.micros as usize / 60000000,
+                                change.micros as usize / 1000000 % 60,
+                                change.bpm()
+                            ));
+                        }
+                    });
+                    let meters = tempo.meters();
+                    let meter = &meters[0];
+                    let text = if meters.len() > 1 {
+                        format!(
+                            "拍号: {}/{} ({}次变拍)",
+                            meter.numerator,
+                            meter.denominator,
+                            meters.len() - 1
+                        )
+                    } else {
+                        format!("拍号: {}/{}", meter.numerator, meter.denominator)
+                    };
+                    ui.label(text).on_hover_ui(|ui| {
+                        for meter in meters {
+                            ui.label(format!(
+                                "第{}小节 {}/{}",
+                                meter.bar, meter.numerator, meter.denominator
+                            ));
+                        }
+                    });
+                });
+            }
         }
         let path = self.config.midi_dir.0.read();
         if !path.is_empty() {
             ui.label(format!("当前目录: {}", path.as_str()));
         }
+        drop(path);
         ui.separator();
         ui.horizontal(|ui| {
             ui.label("选择模式:");
-            ui.radio_value(&mut self.mode, Mode::GenShin, "GenShin");
-            ui.radio_value(&mut self.mode, Mode::VRChat, "VRChat-中文吧");
+            let mut changed = false;
+            egui::ComboBox::from_id_salt("Profile")
+                .selected_text(self.profile.as_str())
+                .show_ui(ui, |ui| {
+                    for name in self.profiles.names() {
+                        let text = name.clone();
+                        changed |= ui.selectable_value(&mut self.profile, name, text).changed();
+                    }
+                });
+            if changed {
+                self.apply_profile();
+            }
         });
         ui.horizontal(|ui| {
             ui.label("播放模式:");
@@ -185,12 +502,161 @@ impl View for Play {
             ui.radio_value(&mut self.play_mode, PlayMode::Loop, "列表循环");
             ui.radio_value(&mut self.play_mode, PlayMode::Random, "列表随机");
         });
+        ui.horizontal(|ui| {
+            ui.label("按键方式:");
+            let sustain = self.config.sustain;
+            if ui.radio(sustain == Sustain::Tap, "点按").clicked() {
+                self.config.sustain = Sustain::Tap;
+            }
+            if ui
+                .radio(matches!(sustain, Sustain::Hold { .. }), "按住音长")
+                .clicked()
+                && sustain == Sustain::Tap
+            {
+                self.config.sustain = Sustain::Hold { cap: None };
+            }
+            if let Sustain::Hold { cap } = &mut self.config.sustain {
+                let mut millis = cap.unwrap_or_default();
+                if ui
+                    .add(
+                        DragValue::new(&mut millis)
+                            .range(0..=10000)
+                            .prefix("最长: ")
+                            .suffix("ms"),
+                    )
+                    .on_hover_text("0 为不限制")
+                    .changed()
+                {
+                    *cap = (millis > 0).then_some(millis);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("超出音域:");
+            ui.radio_value(&mut self.config.fold, Fold::Octave, "八度折叠");
+            ui.radio_value(&mut self.config.fold, Fold::Nearest, "就近音");
+            ui.radio_value(&mut self.config.fold, Fold::Drop, "丢弃");
+            ui.radio_value(&mut self.config.fold, Fold::DropLog, "丢弃并记录");
+            if self.config.fold != self.player.midi.fold.load() {
+                self.player.midi.fold.store(self.config.fold);
+                self.player
+                    .midi
+                    .hit_rate
+                    .store(self.player.midi.detect(self.offset));
+                self.candidates.clear();
+                self.analysis = None;
+            }
+        });
+        ui.horizontal(|ui| {
+            let chord = self.config.chord;
+            let mut max = chord.max.unwrap_or_default();
+            ui.add(DragValue::new(&mut max).range(0..=10).prefix("同时按键: "))
+                .on_hover_text("0 为使用乐器的限制");
+            self.config.chord.max = (max > 0).then_some(max);
+            let strategy = &mut self.config.chord.strategy;
+            ui.radio_value(strategy, Strategy::Highest, "保留高音");
+            ui.radio_value(strategy, Strategy::Lowest, "保留低音");
+            ui.radio_value(strategy, Strategy::MelodyBass, "旋律加低音");
+            if ui
+                .radio(matches!(strategy, Strategy::Arpeggio { .. }), "琶音")
+                .clicked()
+                && !matches!(strategy, Strategy::Arpeggio { .. })
+            {
+                *strategy = Strategy::Arpeggio { spread: 30 };
+            }
+            if let Strategy::Arpeggio { spread } = strategy {
+                ui.add(
+                    DragValue::new(spread)
+                        .range(1..=500)
+                        .prefix("间隔: ")
+                        .suffix("ms"),
+                );
+            }
+            if self.config.chord != chord {
+                self.player.midi.chord.store(self.config.chord);
+                self.notify_merge = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            let ghost = self.config.ghost;
+            ui.label("弱音过滤:");
+            ui.add(
+                DragValue::new(&mut self.config.ghost.min_velocity)
+                    .range(0..=127)
+                    .prefix("最小力度: "),
+            )
+            .on_hover_text("力度低于此值的音符都不弹");
+            ui.add(
+                DragValue::new(&mut self.config.ghost.ghost_velocity)
+                    .range(0..=127)
+                    .prefix("弱音力度: "),
+            )
+            .on_hover_text("力度低于此值的音符为弱音");
+            ui.add(
+                DragValue::new(&mut self.config.ghost.min_gap)
+                    .range(0..=1000)
+                    .prefix("弱音间隔: ")
+                    .suffix("ms"),
+            )
+            .on_hover_text("紧跟上一个音符的弱音不弹");
+            if self.config.ghost != ghost {
+                self.player.midi.ghost.store(self.config.ghost);
+                self.notify_merge = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            let melody = self.config.melody;
+            ui.label("旋律提取:");
+            let voice = &mut self.config.melody.voice;
+            ui.radio_value(voice, Voice::Off, "关闭");
+            ui.radio_value(voice, Voice::Track, "每个音轨");
+            ui.radio_value(voice, Voice::Merged, "合并后");
+            if *voice != Voice::Off {
+                ui.add(
+                    DragValue::new(&mut self.config.melody.window)
+                        .range(0..=500)
+                        .prefix("窗口: ")
+                        .suffix("ms"),
+                )
+                .on_hover_text("只保留此时间内开始的音符中最高的一个");
+            }
+            if self.config.melody != melody {
+                self.player.midi.melody.store(self.config.melody);
+                self.notify_merge = true;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut self.config.spin)
+                    .range(0..=20000)
+                    .prefix("自旋等待: ")
+                    .suffix("µs"),
+            )
+            .on_hover_text("每个音符前忙等的时间，越大越准时但越占用CPU");
+            self.player.settings.store(Settings {
+                sustain: self.config.sustain,
+                spin: self.config.spin,
+            });
+            let lateness = self.player.lateness.read();
+            if !lateness.is_empty() {
+                ui.label(format!(
+                    "延迟: 平均 {}µs 最大 {}µs",
+                    lateness.mean() / 1000,
+                    lateness.max() / 1000
+                ));
+            }
+        });
         ui.separator();
         ui.horizontal(|ui| {
-            ui.add(Slider::new(&mut self.speed, 0.1..=5.0).prefix("播放速度:"));
+            if ui
+                .add(Slider::new(&mut self.speed, 0.1..=5.0).prefix("播放速度:"))
+                .changed()
+            {
+                self.player.control.set_speed(self.speed);
+            }
             if ui.button("还原").clicked() {
                 self.speed = 1.0;
-                SPEED.store(self.speed);
+                self.player.control.set_speed(self.speed);
             }
         });
         ui.horizontal(|ui| {
@@ -200,9 +666,9 @@ impl View for Play {
             }
             if ui.button("减速0.1x").clicked() || sub != self.control_key.sub {
                 self.control_key.sub = sub;
-                if SPEED.load() > 0.1 {
+                if self.player.control.speed() > 0.1 {
                     self.speed -= 0.1;
-                    SPEED.store(self.speed);
+                    self.player.control.set_speed(self.speed);
                 }
             }
             let add = is_pressed(VKey::Equal) || is_pressed(VKey::NpAdd);
@@ -212,7 +678,7 @@ impl View for Play {
             if ui.button("加速0.1x").clicked() || add != self.control_key.add {
                 self.control_key.add = add;
                 self.speed += 0.1;
-                SPEED.store(self.speed);
+                self.player.control.set_speed(self.speed);
             }
         });
         ui.separator();
@@ -220,41 +686,103 @@ impl View for Play {
             ui.label(format!(
                 "偏移量: {} 命中率: {:.2}%",
                 self.offset,
-                self.midi.hit_rate.load() * 100.0
+                self.player.midi.hit_rate.load() * 100.0
             ));
+            let dropped = self.player.dropped.read().len();
+            if self.config.fold == Fold::DropLog && dropped > 0 {
+                ui.label(format!("已丢弃: {}", dropped));
+            }
             if ui.button("还原偏移量").clicked() {
                 self.offset = 0;
-                self.midi.hit_rate.store(self.midi.detect(self.offset));
+                self.player
+                    .midi
+                    .hit_rate
+                    .store(self.player.midi.detect(self.offset));
             }
         });
         if ui.button("向上调音").clicked() {
             self.offset += 1;
-            self.midi.hit_rate.store(self.midi.detect(self.offset));
+            self.player
+                .midi
+                .hit_rate
+                .store(self.player.midi.detect(self.offset));
         }
         if ui.button("向下调音").clicked() {
             self.offset -= 1;
-            self.midi.hit_rate.store(self.midi.detect(self.offset));
+            self.player
+                .midi
+                .hit_rate
+                .store(self.player.midi.detect(self.offset));
+        }
+        ui.horizontal(|ui| {
+            if ui.button("自动调音").clicked() {
+                self.candidates = self
+                    .player
+                    .midi
+                    .auto_tune(-self.tune_range..=self.tune_range);
+                if let Some(&(offset, rate)) = self.candidates.first() {
+                    self.offset = offset;
+                    self.player.midi.hit_rate.store(rate);
+                }
+            }
+            ui.add(
+                DragValue::new(&mut self.tune_range)
+                    .range(0..=48)
+                    .prefix("搜索范围: ±"),
+            );
+        });
+        if !self.candidates.is_empty() {
+            ui.collapsing("候选偏移量", |ui| {
+                for &(offset, rate) in self.candidates.iter().take(5) {
+                    if ui
+                        .selectable_label(
+                            self.offset == offset,
+                            format!("{:+}: {:.2}%", offset, rate * 100.0),
+                        )
+                        .clicked()
+                    {
+                        self.offset = offset;
+                        self.player.midi.hit_rate.store(rate);
+                    }
+                }
+            });
         }
         ui.horizontal(|ui| {
             ui.toggle_value(&mut self.tracks_enable, "音轨列表");
             ui.toggle_value(&mut self.pitch_enable, "音调列表");
             ui.toggle_value(&mut self.map_enable, "按键映射");
+            ui.toggle_value(&mut self.analysis_enable, "命中分析");
         });
         ui.separator();
 
         ui.label(self.state);
-        if STATE.load() != State::Stop {
-            self.progress = LOCAL.load();
-            let count = COUNT.read();
-            let current = self.progress;
+        if let Some(error) = &self.error {
+            ui.colored_label(Color32::RED, error);
+        }
+        if self.player.control.state() != State::Stop {
+            let count = self.player.midi.count.read();
             let len = count.len().saturating_sub(1);
+            let current = self.progress.min(len);
+            let events = self.player.midi.events.read();
+            let bar = match (events.get(current), events.last()) {
+                (
+                    Some(Event { bar: Some(at), .. }),
+                    Some(Event {
+                        bar: Some(last), ..
+                    }),
+                ) => {
+                    format!(" 小节 {at}/{}", last.bar)
+                }
+                _ => String::new(),
+            };
+            drop(events);
             if len > 0
                 && ui
                     .add(
                         Slider::new(&mut self.progress, 0..=len)
                             .show_value(false)
                             .text(format!(
-                                "{:02}:{:02}/{:02}:{:02}",
+                                "{:02}:{:02}/{:02}:{:02}{bar}",
                                 count[current] / 60000000,
                                 count[current] / 1000000 % 60,
                                 count[len] / 60000000,
@@ -263,23 +791,99 @@ impl View for Play {
                     )
                     .drag_stopped()
             {
-                TIME_SHIFT.store(true);
-                LOCAL.store(self.progress);
+                self.player.control.seek(self.progress);
             }
+            ui.horizontal(|ui| {
+                ui.add(
+                    TextEdit::singleline(&mut self.jump)
+                        .hint_text("1:30 或 12.3")
+                        .desired_width(80.0),
+                );
+                if ui.button("跳到时间").clicked() {
+                    match parse_time(&self.jump) {
+                        Some(micros) => self.player.control.seek_time(micros),
+                        None => self.error = Some(format!("无法识别的时间: {}", self.jump)),
+                    }
+                }
+                if ui.button("跳到小节").clicked() {
+                    match parse_bar(&self.jump) {
+                        Some((bar, beat)) => self.player.control.seek_bar(bar, beat),
+                        None => self.error = Some(format!("无法识别的小节: {}", self.jump)),
+                    }
+                }
+            });
         }
+        ui.horizontal(|ui| {
+            let point = |point: Option<usize>| {
+                point.map_or("--:--".to_string(), |micros| {
+                    format!("{:02}:{:02}", micros / 60000000, micros / 1000000 % 60)
+                })
+            };
+            ui.label(format!(
+                "A/B循环: A {} B {}",
+                point(self.ab.a),
+                point(self.ab.b)
+            ));
+            ui.add(
+                TextEdit::singleline(&mut self.ab.text)
+                    .hint_text(if self.ab.by_bar { "12.3" } else { "1:30" })
+                    .desired_width(60.0),
+            );
+            ui.checkbox(&mut self.ab.by_bar, "按小节");
+            if ui.button("设为A").clicked() {
+                self.ab.a = self.loop_point().or(self.ab.a);
+            }
+            if ui.button("设为B").clicked() {
+                self.ab.b = self.loop_point().or(self.ab.b);
+            }
+            if ui.button("清除").clicked() {
+                self.ab.a = None;
+                self.ab.b = None;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut self.ab.times)
+                    .range(0..=999)
+                    .prefix("遍数: "),
+            )
+            .on_hover_text("0为无限循环");
+            ui.add(
+                DragValue::new(&mut self.ab.count_in)
+                    .range(0..=16)
+                    .prefix("预备拍: "),
+            );
+            ui.add(
+                DragValue::new(&mut self.ab.slow_down)
+                    .range(0.0..=50.0)
+                    .speed(0.5)
+                    .prefix("每遍减速: ")
+                    .suffix("%"),
+            );
+            if self.ab.repeat > 0 {
+                ui.label(format!("已重复 {} 次", self.ab.repeat));
+            }
+        });
+        let region = self.ab.region();
+        if region.is_some_and(|region| !region.has_notes(&self.player.midi.count.read())) {
+            ui.colored_label(Color32::YELLOW, "A 与 B 之间没有音符, 不会循环");
+        }
+        self.player.region.store(region);
         ui.separator();
         ui.label("按下 - 键减速");
         ui.label("按下 + 键加速");
+        let bound = self.player.midi.profile.read().distinct_keys();
         ui.horizontal(|ui| {
             ui.label("按下");
             egui::ComboBox::from_id_salt("Play")
                 .selected_text(self.config.function_key.play.as_ref())
                 .show_ui(ui, |ui| {
                     VKey::iter()
-                        .filter(|k| unsafe {
+                        .filter(|k| {
                             self.config.function_key.pause.ne(k)
                                 && self.config.function_key.stop.ne(k)
-                                && !MAP.contains(k)
+                                && self.config.function_key.mark.ne(k)
+                                && !bound.contains(k)
                         })
                         .for_each(|key| {
                             ui.selectable_value(
@@ -297,10 +901,11 @@ impl View for Play {
                 .selected_text(self.config.function_key.pause.as_ref())
                 .show_ui(ui, |ui| {
                     VKey::iter()
-                        .filter(|k| unsafe {
+                        .filter(|k| {
                             self.config.function_key.play.ne(k)
                                 && self.config.function_key.stop.ne(k)
-                                && !MAP.contains(k)
+                                && self.config.function_key.mark.ne(k)
+                                && !bound.contains(k)
                         })
                         .for_each(|key| {
                             ui.selectable_value(
@@ -318,10 +923,11 @@ impl View for Play {
                 .selected_text(self.config.function_key.stop.as_ref())
                 .show_ui(ui, |ui| {
                     VKey::iter()
-                        .filter(|k| unsafe {
+                        .filter(|k| {
                             self.config.function_key.play.ne(k)
                                 && self.config.function_key.pause.ne(k)
-                                && !MAP.contains(k)
+                                && self.config.function_key.mark.ne(k)
+                                && !bound.contains(k)
                         })
                         .for_each(|key| {
                             ui.selectable_value(
@@ -333,36 +939,71 @@ impl View for Play {
                 });
             ui.label("键停止播放");
         });
+        ui.horizontal(|ui| {
+            ui.label("按下");
+            egui::ComboBox::from_id_salt("Mark")
+                .selected_text(self.config.function_key.mark.as_ref())
+                .show_ui(ui, |ui| {
+                    VKey::iter()
+                        .filter(|k| {
+                            self.config.function_key.play.ne(k)
+                                && self.config.function_key.pause.ne(k)
+                                && self.config.function_key.stop.ne(k)
+                                && !bound.contains(k)
+                        })
+                        .for_each(|key| {
+                            ui.selectable_value(
+                                &mut self.config.function_key.mark,
+                                key,
+                                key.as_ref(),
+                            );
+                        })
+                });
+            ui.label("键标记循环的A点 | B点");
+        });
         ui.label("");
         ui.label("注意: 每±12个偏移量为一个八度");
 
         if is_pressed(self.config.function_key.play) {
-            match STATE.load() {
-                State::Stop => {
-                    if !PLAYING.load() {
-                        let midi = self.midi.clone();
-                        midi.playback_by(
-                            self.config.midi_dir.0.read().as_str(),
-                            self.offset,
-                            self.play_mode,
-                            self.mode,
-                        );
-                    }
+            match self.player.control.state() {
+                State::Stop if !self.player.is_running() => {
+                    self.player.playback_by(
+                        self.config.midi_dir.0.read().as_str(),
+                        self.offset,
+                        self.play_mode,
+                        self.output.clone(),
+                    );
                 }
-                State::Pause => STATE.store(State::Playing),
+                State::Pause => self.player.control.resume(),
                 _ => {}
             }
         }
-        if is_pressed(self.config.function_key.stop) {
-            STATE.store(State::Stop);
+        if is_pressed(self.config.function_key.stop) && self.player.control.state() != State::Stop {
+            self.player.control.stop();
         }
-        if is_pressed(self.config.function_key.pause) {
-            if let State::Playing = STATE.load() {
-                STATE.store(State::Pause);
+        if is_pressed(self.config.function_key.pause)
+            && self.player.control.state() == State::Playing
+        {
+            self.player.control.pause();
+        }
+
+        let mark = is_pressed(self.config.function_key.mark);
+        if !mark {
+            self.control_key.mark = false;
+        } else if !self.control_key.mark {
+            self.control_key.mark = true;
+            if self.player.control.state() != State::Stop {
+                let position = self.player.midi.time_of(Seek::Event(self.progress));
+                if self.ab.a.is_none() || self.ab.b.is_some() {
+                    self.ab.a = position;
+                    self.ab.b = None;
+                } else {
+                    self.ab.b = position;
+                }
             }
         }
 
-        self.state = match STATE.load() {
+        self.state = match self.player.control.state() {
             State::Playing => "播放中...",
             State::Pause => "已暂停",
             State::Stop => "已停止",
@@ -2,7 +2,20 @@ use serde::{Deserialize, Serialize};
 use strum::{AsRefStr, EnumIter};
 
 #[repr(u16)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, AsRefStr, Serialize, Deserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    EnumIter,
+    AsRefStr,
+    Serialize,
+    Deserialize,
+)]
 pub enum VKey {
     A = 65,
     B = 66,
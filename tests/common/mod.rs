@@ -0,0 +1,68 @@
+//! Builds MIDI files for the tests byte by byte
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+/// A tempo event at tick 0 of `mpq` microseconds per quarter note
+pub fn tempo(mpq: u32) -> (u32, Vec<u8>) {
+    let [_, a, b, c] = mpq.to_be_bytes();
+    (0, vec![0xFF, 0x51, 0x03, a, b, c])
+}
+
+/// The note on and off messages of `(start, length, note)` on `channel` counted from 0,
+/// ordered by tick with the releases before the presses of the same tick
+pub fn notes(channel: u8, notes: &[(u32, u32, u8)]) -> Vec<(u32, Vec<u8>)> {
+    let mut messages = vec![];
+    for &(start, len, note) in notes {
+        messages.push((start, 1, vec![0x90 | channel, note, 100]));
+        messages.push((start + len, 0, vec![0x80 | channel, note, 0]));
+    }
+    messages.sort_by_key(|(tick, on, _)| (*tick, *on));
+    messages
+        .into_iter()
+        .map(|(tick, _, bytes)| (tick, bytes))
+        .collect()
+}
+
+/// The bytes of a track of `(tick, message)` events, which must be in order,
+/// followed by the end of the track
+pub fn track(events: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut track = vec![];
+    let mut last = 0;
+    for (tick, bytes) in events {
+        let delta = tick - last;
+        last = *tick;
+        let mut var = vec![(delta & 0x7F) as u8];
+        let mut rest = delta >> 7;
+        while rest > 0 {
+            var.insert(0, (rest & 0x7F) as u8 | 0x80);
+            rest >>= 7;
+        }
+        track.extend(var);
+        track.extend_from_slice(bytes);
+    }
+    track.extend([0x00, 0xFF, 0x2F, 0x00]);
+    track
+}
+
+/// A file of `format` with the raw `division` of the header
+pub fn file(format: u16, division: [u8; 2], tracks: &[Vec<u8>]) -> Vec<u8> {
+    let mut file = b"MThd".to_vec();
+    file.extend(6u32.to_be_bytes());
+    file.extend(format.to_be_bytes());
+    file.extend((tracks.len() as u16).to_be_bytes());
+    file.extend(division);
+    for track in tracks {
+        file.extend(b"MTrk");
+        file.extend((track.len() as u32).to_be_bytes());
+        file.extend(track);
+    }
+    file
+}
+
+/// Writes `bytes` into a temporary file named after the test
+pub fn write(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("lyred-{name}.mid"));
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
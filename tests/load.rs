@@ -0,0 +1,75 @@
+use lyred::midi::{LoadError, Midi};
+use midly::Timing;
+
+mod common;
+
+/// A single track file of notes 60 and 62 at 120 BPM with the raw `division` of the header
+fn song(division: [u8; 2]) -> Vec<u8> {
+    let mut events = vec![common::tempo(500_000)];
+    events.extend(common::notes(0, &[(0, 48, 60), (96, 48, 62)]));
+    common::file(0, division, &[common::track(&events)])
+}
+
+/// Loads `bytes` into `midi` from a temporary file named after the test
+fn load(midi: &Midi, name: &str, bytes: &[u8]) -> Result<(), LoadError> {
+    let path = common::write(&format!("load-{name}"), bytes);
+    let loaded = midi.read_midi(&path);
+    std::fs::remove_file(path).ok();
+    loaded
+}
+
+#[test]
+fn truncated_track_is_loaded() {
+    let mut bytes = song([0, 96]);
+    // Cut the file in the middle of the release of the second note
+    bytes.truncate(bytes.len() - 6);
+    let midi = Midi::new();
+    load(&midi, "truncated", &bytes).unwrap();
+    let events = midi.events.read();
+    assert_eq!(
+        events.iter().map(|e| e.press).collect::<Vec<_>>(),
+        [60, 62],
+        "{events:?}"
+    );
+}
+
+#[test]
+fn garbage_is_not_parsed() {
+    let midi = Midi::new();
+    load(&midi, "good", &song([0, 96])).unwrap();
+    let loaded = load(&midi, "garbage", b"this is not a MIDI file at all");
+    assert!(matches!(loaded, Err(LoadError::Parse(_))), "{loaded:?}");
+    // The song loaded before is kept
+    assert_eq!(midi.events.read().len(), 2);
+}
+
+#[test]
+fn zero_division_is_unsupported() {
+    let midi = Midi::new();
+    let loaded = load(&midi, "tpq-0", &song([0, 0]));
+    assert!(
+        matches!(
+            loaded,
+            Err(LoadError::UnsupportedTiming(Timing::Metrical(_)))
+        ),
+        "{loaded:?}"
+    );
+    // 25 fps without subframes
+    let loaded = load(&midi, "subframes-0", &song([25u8.wrapping_neg(), 0]));
+    assert!(
+        matches!(
+            loaded,
+            Err(LoadError::UnsupportedTiming(Timing::Timecode(..)))
+        ),
+        "{loaded:?}"
+    );
+}
+
+#[test]
+fn file_without_notes_is_empty() {
+    // Only a tempo and a program change
+    let track = common::track(&[common::tempo(500_000), (0, vec![0xC0, 0x2E])]);
+    let midi = Midi::new();
+    let loaded = load(&midi, "empty", &common::file(0, [0, 96], &[track]));
+    assert!(matches!(loaded, Err(LoadError::Empty)), "{loaded:?}");
+}
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use lyred::midi::{Midi, Percussion, Split};
+
+mod common;
+
+/// One track with C# and F# on channel 1 and two drums on channel 10
+fn write_midi() -> PathBuf {
+    let mut notes = common::notes(0, &[(0, 48, 61), (96, 48, 66)]);
+    notes.extend(common::notes(9, &[(48, 10, 36), (144, 10, 38)]));
+    notes.sort_by_key(|(tick, _)| *tick);
+    let mut events = vec![common::tempo(500_000)];
+    events.extend(notes);
+    common::write(
+        "percussion",
+        &common::file(0, [0, 96], &[common::track(&events)]),
+    )
+}
+
+/// The song in one track, with the percussion handled as `percussion`
+fn load(percussion: Percussion) -> Midi {
+    let midi = Midi::new();
+    midi.split.store(Split::Off);
+    midi.percussion.store(percussion);
+    let path = write_midi();
+    midi.read_midi(&path).unwrap();
+    std::fs::remove_file(path).ok();
+    midi
+}
+
+/// The `(note, percussion)` of every event
+fn notes(midi: &Midi) -> Vec<(i32, bool)> {
+    midi.events
+        .read()
+        .iter()
+        .map(|e| (e.press, e.percussion))
+        .collect()
+}
+
+#[test]
+fn drums_are_skipped_by_default() {
+    let midi = load(Percussion::default());
+    assert_eq!(notes(&midi), [(61, false), (66, false)]);
+}
+
+#[test]
+fn drums_are_mapped() {
+    let midi = load(Percussion::Drums);
+    assert_eq!(
+        notes(&midi),
+        [(61, false), (36, true), (66, false), (38, true)]
+    );
+    // Played with the drum profile whatever the offset is
+    let profile = midi.profile.read();
+    let drums = midi.drums.read();
+    let fold = midi.fold.load();
+    for e in midi.events.read().iter().filter(|e| e.percussion) {
+        let key = e.key(7, &profile, &drums, fold);
+        assert!(key.is_some());
+        assert_eq!(key, drums.key(e.press));
+    }
+}
+
+#[test]
+fn drums_are_not_transposed() {
+    let midi = load(Percussion::Drums);
+    midi.mixer.write()[0].transpose = 5;
+    midi.merge_tracks(&midi.current_range(), 0);
+    assert_eq!(
+        notes(&midi),
+        [(66, false), (36, true), (71, false), (38, true)]
+    );
+}
+
+#[test]
+fn drums_are_not_auto_tuned() {
+    let midi = load(Percussion::Drums);
+    let tuned = midi.auto_tune_tracks(-12..=12, 0);
+    let best = tuned[0].1[0].0;
+    // C# and F# are off the keys of the default profile
+    assert_ne!(best, 0);
+    midi.merge_tracks(&midi.current_range(), 0);
+    assert_eq!(
+        notes(&midi),
+        [
+            (61 + best, false),
+            (36, true),
+            (66 + best, false),
+            (38, true)
+        ]
+    );
+}
@@ -0,0 +1,399 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use lyred::chord::{ChordLimit, Strategy};
+use lyred::control::Controller;
+use lyred::midi::{Midi, Sustain};
+use lyred::observe::PlayerEvent;
+use lyred::output::{KeyAction, Recorder};
+use lyred::player::{LoopRegion, Player, Settings};
+use lyred::profile::InstrumentProfile;
+use lyred::util::VKey;
+
+use KeyAction::{Press, Release};
+
+mod common;
+
+/// Keys of notes 60 to 72
+const KEYS: [VKey; 13] = [
+    VKey::A,
+    VKey::B,
+    VKey::C,
+    VKey::D,
+    VKey::E,
+    VKey::F,
+    VKey::G,
+    VKey::H,
+    VKey::I,
+    VKey::J,
+    VKey::K,
+    VKey::L,
+    VKey::M,
+];
+
+fn key(note: i32) -> VKey {
+    KEYS[(note - 60) as usize]
+}
+
+/// A single track file where a tick lasts a millisecond and a beat 100 ms,
+/// with a `(start, length, note)` for every note
+fn write_midi(name: &str, notes: &[(u32, u32, u8)]) -> PathBuf {
+    let mut events = vec![common::tempo(100_000)];
+    events.extend(common::notes(0, notes));
+    common::write(
+        &format!("playback-{name}"),
+        &common::file(0, 100u16.to_be_bytes(), &[common::track(&events)]),
+    )
+}
+
+/// A player of the notes with every note from 60 to 72 bound and no limits
+fn player(name: &str, notes: &[(u32, u32, u8)], sustain: Sustain) -> Player {
+    let midi = Midi::new();
+    *midi.profile.write() = InstrumentProfile {
+        name: "Test".to_string(),
+        keys: (60..=72).map(|note| (note, key(note))).collect(),
+        range: None,
+        max_chord: None,
+        min_interval: 0,
+        key_interval: Default::default(),
+        labels: Default::default(),
+    };
+    let path = write_midi(name, notes);
+    midi.read_midi(&path).unwrap();
+    std::fs::remove_file(path).ok();
+    let player = Player::with_midi(midi);
+    player.settings.store(Settings {
+        sustain,
+        ..Settings::default()
+    });
+    player
+}
+
+fn play(player: &Player) -> Vec<KeyAction> {
+    let recorder = Recorder::default();
+    player.control.play();
+    player.playback(0, &recorder);
+    recorder.take()
+}
+
+#[test]
+fn tap_releases_at_once() {
+    let player = player("tap", &[(0, 200, 60), (100, 50, 62)], Sustain::Tap);
+    assert_eq!(
+        play(&player),
+        [
+            Press(key(60)),
+            Release(key(60)),
+            Press(key(62)),
+            Release(key(62))
+        ]
+    );
+}
+
+#[test]
+fn hold_releases_after_the_note() {
+    let player = player(
+        "hold",
+        &[(0, 200, 60), (100, 50, 62)],
+        Sustain::Hold { cap: None },
+    );
+    assert_eq!(
+        play(&player),
+        [
+            Press(key(60)),
+            Press(key(62)),
+            Release(key(62)),
+            Release(key(60))
+        ]
+    );
+}
+
+#[test]
+fn hold_is_capped() {
+    let player = player(
+        "cap",
+        &[(0, 200, 60), (100, 50, 62)],
+        Sustain::Hold { cap: Some(50) },
+    );
+    assert_eq!(
+        play(&player),
+        [
+            Press(key(60)),
+            Release(key(60)),
+            Press(key(62)),
+            Release(key(62))
+        ]
+    );
+}
+
+#[test]
+fn chord_is_pressed_together() {
+    let player = player(
+        "chord",
+        &[(0, 50, 60), (0, 50, 64), (0, 50, 67), (100, 50, 72)],
+        Sustain::Tap,
+    );
+    let mut actions = play(&player);
+    // Every key of the chord goes down before any goes up
+    let order = |action: &KeyAction| match action {
+        Press(vk) => (0, *vk),
+        Release(vk) => (1, *vk),
+    };
+    actions[..6].sort_by_key(order);
+    assert_eq!(
+        actions,
+        [
+            Press(key(60)),
+            Press(key(64)),
+            Press(key(67)),
+            Release(key(60)),
+            Release(key(64)),
+            Release(key(67)),
+            Press(key(72)),
+            Release(key(72))
+        ]
+    );
+}
+
+/// The keys pressed after pausing on the first note, calling `seek` and resuming
+fn seek_while_paused(player: &Player, seek: impl FnOnce(&Controller)) -> Vec<VKey> {
+    let events = player.subscribe();
+    let recorder = Recorder::default();
+    player.control.play();
+    thread::scope(|scope| {
+        scope.spawn(|| player.playback(0, &recorder));
+        while !matches!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            PlayerEvent::Note { .. }
+        ) {}
+        player.control.pause();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(recorder.pressed(), [key(60)]);
+
+        seek(&player.control);
+        player.control.resume();
+    });
+    recorder.pressed()[1..].to_vec()
+}
+
+#[test]
+fn pause_then_seek() {
+    let notes = [60, 62, 64, 65, 67, 69, 71, 72]
+        .iter()
+        .enumerate()
+        .map(|(i, note)| (i as u32 * 100, 50, *note))
+        .collect::<Vec<_>>();
+    let player = player("seek", &notes, Sustain::Tap);
+    assert_eq!(
+        seek_while_paused(&player, |control| control.seek(5)),
+        [key(69), key(71), key(72)]
+    );
+}
+
+/// Notes 100 ms apart with a chord of 65, 67 and 69 at 300 ms, in a 4/4 bar of 400 ms
+const WITH_CHORD: [(u32, u32, u8); 8] = [
+    (0, 50, 60),
+    (100, 50, 62),
+    (200, 50, 64),
+    (300, 50, 65),
+    (300, 50, 67),
+    (300, 50, 69),
+    (400, 50, 71),
+    (500, 50, 72),
+];
+
+/// The keys pressed after seeking, with the keys of the chord that comes first in order
+fn seek_to(name: &str, seek: impl FnOnce(&Controller)) -> Vec<VKey> {
+    let player = player(name, &WITH_CHORD, Sustain::Tap);
+    let mut pressed = seek_while_paused(&player, seek);
+    if pressed.len() > 3 {
+        pressed[..3].sort();
+    }
+    pressed
+}
+
+#[test]
+fn seek_time_goes_to_the_next_chord() {
+    let chord_then_rest = [key(65), key(67), key(69), key(71), key(72)];
+    // Between two chords
+    assert_eq!(
+        seek_to("seek-between", |control| control.seek_time(250_000)),
+        chord_then_rest
+    );
+    // On a chord, and a microsecond of rounding after it, every key of it is pressed
+    assert_eq!(
+        seek_to("seek-on", |control| control.seek_time(300_000)),
+        chord_then_rest
+    );
+    assert_eq!(
+        seek_to("seek-rounded", |control| control.seek_time(300_001)),
+        chord_then_rest
+    );
+    // Past the end nothing is left
+    assert_eq!(
+        seek_to("seek-end", |control| control.seek_time(10_000_000)),
+        []
+    );
+}
+
+#[test]
+fn seek_bar_goes_to_the_beat() {
+    // The fourth beat of the first bar falls on the chord
+    assert_eq!(
+        seek_to("bar-chord", |control| control.seek_bar(1, 4)),
+        [key(65), key(67), key(69), key(71), key(72)]
+    );
+    assert_eq!(
+        seek_to("bar-second", |control| control.seek_bar(2, 2)),
+        [key(72)]
+    );
+}
+
+#[test]
+fn chord_limit_of_the_user_wins() {
+    let player = player(
+        "limit",
+        &[(0, 50, 60), (0, 50, 64), (0, 50, 67)],
+        Sustain::Tap,
+    );
+    player.midi.profile.write().max_chord = Some(1);
+    player.midi.chord.store(ChordLimit {
+        max: Some(2),
+        strategy: Strategy::MelodyBass,
+    });
+    player.midi.merge_tracks(&player.midi.current_range(), 0);
+    let mut pressed = play(&player)
+        .into_iter()
+        .filter(|action| matches!(action, Press(_)))
+        .collect::<Vec<_>>();
+    pressed.sort_by_key(|action| match action {
+        Press(vk) | Release(vk) => *vk,
+    });
+    assert_eq!(pressed, [Press(key(60)), Press(key(67))]);
+}
+
+/// A pressed key and the millisecond it was due
+type Pressed = (VKey, u64);
+
+/// The pressed keys and the `(repeat, speed)` of every pass
+/// when `region` is looped from the start of playback
+fn play_region(player: &Player, region: LoopRegion) -> (Vec<Pressed>, Vec<(u32, f32)>) {
+    let events = player.subscribe();
+    player.region.store(Some(region));
+    play(player);
+    let mut notes = vec![];
+    let mut passes = vec![];
+    for event in events.try_iter() {
+        match event {
+            PlayerEvent::Note { key, time, .. } => notes.push((key, (time + 500_000) / 1_000_000)),
+            PlayerEvent::Looped { repeat, speed } => passes.push((repeat, speed)),
+            _ => {}
+        }
+    }
+    (notes, passes)
+}
+
+/// Notes 60, 62, 64 and 65 a hundred milliseconds apart
+const STEPS: [(u32, u32, u8); 4] = [(0, 50, 60), (100, 50, 62), (200, 50, 64), (300, 50, 65)];
+
+#[test]
+fn loop_then_go_on() {
+    let player = player("loop", &STEPS, Sustain::Tap);
+    let region = LoopRegion {
+        times: Some(2),
+        ..LoopRegion::new(100_000, 250_000).unwrap()
+    };
+    let (notes, passes) = play_region(&player, region);
+    // Playback starts at A, B comes 50 ms after the last chord of the passage
+    assert_eq!(
+        notes,
+        [
+            (key(62), 0),
+            (key(64), 100),
+            (key(62), 150),
+            (key(64), 250),
+            (key(65), 350)
+        ]
+    );
+    assert_eq!(passes, [(1, 1.0)]);
+}
+
+#[test]
+fn loop_without_notes_plays_the_song() {
+    let player = player("empty-loop", &STEPS, Sustain::Tap);
+    let region = LoopRegion::new(260_000, 290_000).unwrap();
+    assert!(!region.has_notes(&[0, 100_000, 200_000, 300_000]));
+    assert!(region.has_notes(&[0, 100_000, 200_000, 270_000]));
+    let (notes, passes) = play_region(&player, region);
+    assert_eq!(
+        notes.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+        [key(60), key(62), key(64), key(65)]
+    );
+    assert!(passes.is_empty());
+}
+
+#[test]
+fn endless_loop_is_stopped() {
+    let player = player("endless", &STEPS, Sustain::Tap);
+    player.region.store(LoopRegion::new(100_000, 250_000));
+    let events = player.subscribe();
+    let recorder = Recorder::default();
+    player.control.play();
+    thread::scope(|scope| {
+        scope.spawn(|| player.playback(0, &recorder));
+        while !matches!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            PlayerEvent::Looped { repeat: 3, .. }
+        ) {}
+        player.control.stop();
+    });
+    let pressed = recorder.pressed();
+    assert!(pressed.len() >= 6, "{pressed:?}");
+    for pass in pressed.chunks(2) {
+        assert_eq!(pass[0], key(62));
+        assert!(pass.len() == 1 || pass[1] == key(64), "{pressed:?}");
+    }
+}
+
+#[test]
+fn count_in_before_each_pass() {
+    let player = player("count-in", &[(0, 50, 60), (100, 50, 62)], Sustain::Tap);
+    let region = LoopRegion {
+        times: Some(2),
+        count_in: 1,
+        ..LoopRegion::new(0, 100_000).unwrap()
+    };
+    let (notes, _) = play_region(&player, region);
+    // A beat of 100 ms before both passes, none after the last one
+    assert_eq!(notes, [(key(60), 100), (key(60), 300), (key(62), 400)]);
+}
+
+#[test]
+fn loop_slows_down() {
+    let player = player(
+        "slow-down",
+        &[(0, 20, 60), (50, 20, 62), (100, 20, 64)],
+        Sustain::Tap,
+    );
+    let region = LoopRegion {
+        times: Some(2),
+        slow_down: 0.5,
+        ..LoopRegion::new(0, 100_000).unwrap()
+    };
+    assert_eq!(region.rate(2), 0.25);
+    assert_eq!(region.rate(10), 0.1);
+    let (notes, passes) = play_region(&player, region);
+    // The second pass takes twice as long, the rest of the song is at full speed
+    assert_eq!(
+        notes,
+        [
+            (key(60), 0),
+            (key(62), 50),
+            (key(60), 100),
+            (key(62), 200),
+            (key(64), 300)
+        ]
+    );
+    assert_eq!(passes, [(1, 0.5)]);
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use lyred::midi::{Midi, Split};
+
+mod common;
+
+/// A type 0 file with a flute on channel 1, a violin on channel 2 and drums on channel 10
+fn write_midi() -> PathBuf {
+    let mut events = vec![
+        common::tempo(500_000),
+        (0, vec![0xC0, 73]),
+        (0, vec![0xC1, 40]),
+    ];
+    let mut notes = common::notes(0, &[(0, 48, 72), (96, 48, 74)]);
+    notes.extend(common::notes(1, &[(0, 96, 60), (96, 96, 62)]));
+    notes.extend(common::notes(9, &[(48, 10, 36), (144, 10, 38)]));
+    // Stable, so the releases of a channel stay before its presses
+    notes.sort_by_key(|(tick, _)| *tick);
+    events.extend(notes);
+    common::write(
+        "split",
+        &common::file(0, [0, 96], &[common::track(&events)]),
+    )
+}
+
+#[test]
+fn type_0_is_split_by_channel() {
+    let path = write_midi();
+    let midi = Midi::new();
+    midi.read_midi(&path).unwrap();
+    std::fs::remove_file(path).ok();
+
+    assert_eq!(
+        *midi.track_num.read(),
+        [
+            (true, 0, "Ch1 Flute".to_string()),
+            (true, 1, "Ch2 Violin".to_string()),
+            (true, 2, "Ch10 Drums".to_string()),
+        ]
+    );
+    let percussion = (0..3).map(|i| midi.is_percussion(i)).collect::<Vec<_>>();
+    assert_eq!(percussion, [false, false, true]);
+    let notes = midi.tracks.read().iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(notes, [2, 2, 2]);
+
+    // Not split, the whole file is one track named after its first instrument
+    midi.set_split(Split::Off);
+    assert_eq!(*midi.track_num.read(), [(true, 0, "Flute".to_string())]);
+    assert!(!midi.is_percussion(0));
+}
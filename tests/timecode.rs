@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use lyred::midi::{Division, Midi};
+
+mod common;
+
+/// A single track file: a tempo of 250000 µs per quarter note,
+/// a note at tick 0 released at `second / 2` and a note at tick `second`
+fn write_midi(name: &str, division: [u8; 2], second: u32) -> PathBuf {
+    let mut events = vec![common::tempo(250_000)];
+    events.extend(common::notes(
+        0,
+        &[(0, second / 2, 60), (second, second / 2, 62)],
+    ));
+    common::write(name, &common::file(0, division, &[common::track(&events)]))
+}
+
+/// Loads a timecode file at `fps` with `subframes` and checks that
+/// one second of ticks lasts one second whatever the tempo is
+fn check_timecode(fps: i8, subframes: u8, ticks_per_second: f32) {
+    let second = ticks_per_second.round() as u32;
+    let path = write_midi(
+        &format!("timecode-{fps}-{subframes}"),
+        [fps.wrapping_neg() as u8, subframes],
+        second,
+    );
+    let midi = Midi::new();
+    midi.read_midi(&path).unwrap();
+    std::fs::remove_file(path).ok();
+
+    let division = midi.tempo.read().division();
+    let Division::Timecode(tps) = division else {
+        panic!("{division:?} is not timecode");
+    };
+    assert!((tps - ticks_per_second).abs() < 0.01, "{tps}");
+    let events = midi.events.read();
+    assert_eq!(events.len(), 2);
+    let micros = |ticks: u32| ticks as f32 * 1_000_000.0 / ticks_per_second;
+    assert!((events[1].delay - micros(second)).abs() < 1.0, "{events:?}");
+    assert!(
+        (events[0].duration - micros(second / 2)).abs() < 1.0,
+        "{events:?}"
+    );
+    assert!((events[1].delay - 1_000_000.0).abs() < 1.0, "{events:?}");
+}
+
+#[test]
+fn timecode_24_fps() {
+    check_timecode(24, 40, 960.0);
+}
+
+#[test]
+fn timecode_25_fps() {
+    check_timecode(25, 40, 1000.0);
+}
+
+#[test]
+fn timecode_29_97_fps() {
+    check_timecode(29, 100, 2997.0);
+}
+
+#[test]
+fn timecode_30_fps() {
+    check_timecode(30, 80, 2400.0);
+}
+
+#[test]
+fn metrical_follows_tempo() {
+    let path = write_midi("metrical", 480u16.to_be_bytes(), 480);
+    let midi = Midi::new();
+    midi.read_midi(&path).unwrap();
+    std::fs::remove_file(path).ok();
+
+    assert_eq!(midi.tempo.read().division(), Division::Metrical(480.0));
+    let events = midi.events.read();
+    assert_eq!(events[1].delay, 250_000.0);
+    assert_eq!(events[0].duration, 125_000.0);
+}